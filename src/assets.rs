@@ -0,0 +1,239 @@
+use std::{
+    io::ErrorKind,
+    path::{Path as FsPath, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Request, State},
+    http::{
+        header::{AUTHORIZATION, CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tower_http::services::ServeDir;
+
+use crate::{
+    cch_error::{ReportError, StatusError},
+    ServerState,
+};
+
+/// Suffixes of the pre-compressed variants served next to an asset
+const COMPRESSED_SUFFIXES: &[&str] = &["gz", "br"];
+
+const CACHE_POLICY: &str = "public, max-age=3600, must-revalidate";
+
+/// The directory served under `/11/assets` and the token that allows
+/// changing it, uploads and deletes are refused while no token is set
+#[derive(Debug, Clone)]
+pub struct AssetStore {
+    pub dir: PathBuf,
+    token: Option<String>,
+}
+
+impl AssetStore {
+    pub fn new(dir: impl Into<PathBuf>, token: Option<String>) -> Self {
+        AssetStore {
+            dir: dir.into(),
+            token,
+        }
+    }
+
+    /// Serves the assets with `Last-Modified`, conditional requests, ranges
+    /// and `.gz`/`.br` variants when the client accepts them
+    pub fn service(&self) -> ServeDir {
+        ServeDir::new(&self.dir)
+            .precompressed_gzip()
+            .precompressed_br()
+    }
+
+    /// The path of a top level asset, names can't leave the directory
+    pub fn path(&self, name: &str) -> Result<PathBuf, StatusError> {
+        let plain = !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']);
+        if !plain {
+            return Err(StatusError::bad_request(format!(
+                "{name} isn't an asset name"
+            )));
+        }
+        Ok(self.dir.join(name))
+    }
+
+    /// Checks the bearer token that admin changes need
+    pub fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusError> {
+        let token = self
+            .token
+            .as_deref()
+            .ok_or_else(|| StatusError::new(StatusCode::FORBIDDEN, "Admin changes are disabled"))?;
+        let given = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| StatusError::new(StatusCode::UNAUTHORIZED, "Missing bearer token"))?;
+        // comparing digests keeps the time taken independent of the token
+        if Sha256::digest(given) != Sha256::digest(token) {
+            return Err(StatusError::new(StatusCode::UNAUTHORIZED, "Wrong token"));
+        }
+        Ok(())
+    }
+}
+
+/// Weak validator from the size and modification time of the file
+async fn etag(path: &FsPath) -> Option<String> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "W/\"{:x}-{:x}\"",
+        metadata.len(),
+        modified.as_nanos()
+    ))
+}
+
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+/// Adds `ETag` and `Cache-Control` to served assets and answers
+/// `If-None-Match` requests that are still fresh with a 304
+pub async fn asset_headers(
+    State(state): State<ServerState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let name = request.uri().path().trim_start_matches('/').to_string();
+    let etag = match state.assets.path(&name) {
+        Ok(path) => etag(&path).await,
+        Err(_) => None,
+    };
+    if let Some(etag) = &etag {
+        if matches_etag(request.headers(), etag) {
+            return (
+                StatusCode::NOT_MODIFIED,
+                [(ETAG, etag.as_str()), (CACHE_CONTROL, CACHE_POLICY)],
+            )
+                .into_response();
+        }
+    }
+    let mut response = next.run(request).await;
+    let served = response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED;
+    if let (true, Some(etag)) = (served, etag) {
+        let headers = response.headers_mut();
+        if let Ok(etag) = HeaderValue::from_str(&etag) {
+            headers.insert(ETAG, etag);
+        }
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(CACHE_POLICY));
+    }
+    response
+}
+
+#[derive(Debug, Serialize)]
+pub struct AssetInfo {
+    name: String,
+    size: u64,
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn list_assets(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<AssetInfo>>, ReportError> {
+    state.assets.authorize(&headers)?;
+    let mut entries = tokio::fs::read_dir(&state.assets.dir).await?;
+    let mut assets = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_file() && !name.starts_with('.') {
+            assets.push(AssetInfo {
+                name,
+                size: metadata.len(),
+            });
+        }
+    }
+    assets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(assets))
+}
+
+/// Removes the pre-compressed variants so they can't outlive their asset
+async fn remove_variants(path: &FsPath) -> Result<(), std::io::Error> {
+    for suffix in COMPRESSED_SUFFIXES {
+        let mut variant = path.as_os_str().to_owned();
+        variant.push(format!(".{suffix}"));
+        match tokio::fs::remove_file(&variant).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Creates or replaces an asset with the request body
+#[tracing::instrument(skip(state, headers, body), fields(size = body.len()))]
+pub async fn upload_asset(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, ReportError> {
+    state.assets.authorize(&headers)?;
+    let path = state.assets.path(&name)?;
+    let existed = tokio::fs::try_exists(&path).await?;
+    tokio::fs::create_dir_all(&state.assets.dir).await?;
+    // written aside first so the asset is never served half written
+    let partial = state
+        .assets
+        .dir
+        .join(format!(".{name}.{}", ulid::Ulid::new()));
+    tokio::fs::write(&partial, &body).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    remove_variants(&path).await?;
+    Ok(if existed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    })
+}
+
+#[tracing::instrument(skip(state, headers))]
+pub async fn delete_asset(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ReportError> {
+    state.assets.authorize(&headers)?;
+    let path = state.assets.path(&name)?;
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return Err(StatusError::new(StatusCode::NOT_FOUND, format!("No asset {name}")).into())
+        }
+        Err(err) => return Err(err.into()),
+    }
+    remove_variants(&path).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The bytes of an asset, for handlers that work on assets
+pub async fn read_asset(state: &ServerState, name: &str) -> Result<Vec<u8>, ReportError> {
+    match tokio::fs::read(state.assets.path(name)?).await {
+        Ok(data) => Ok(data),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            Err(StatusError::new(StatusCode::NOT_FOUND, format!("No asset {name}")).into())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
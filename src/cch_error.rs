@@ -1,4 +1,9 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use std::fmt::Display;
+
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::IntoResponse,
+};
 use color_eyre::eyre::Report;
 
 pub struct ReportError(pub Report);
@@ -12,12 +17,68 @@ where
     }
 }
 
+impl ReportError {
+    /// the status this error is reported with
+    pub fn status(&self) -> StatusCode {
+        self.0
+            .downcast_ref::<StatusError>()
+            .map_or(StatusCode::INTERNAL_SERVER_ERROR, |err| err.status)
+    }
+}
+
 impl IntoResponse for ReportError {
     fn into_response(self) -> axum::response::Response {
+        let status = self.status();
+        let retry_after = self
+            .0
+            .downcast_ref::<StatusError>()
+            .and_then(|err| err.retry_after)
+            .map(|secs| [(RETRY_AFTER, secs.to_string())]);
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Internal Server Error: {:?}", self.0),
+            status,
+            retry_after,
+            format!(
+                "{}: {:?}",
+                status.canonical_reason().unwrap_or_default(),
+                self.0
+            ),
         )
             .into_response()
     }
 }
+
+/// An error that should be reported with a status other than 500
+#[derive(Debug, Clone)]
+pub struct StatusError {
+    pub status: StatusCode,
+    pub message: String,
+    /// seconds sent in a `Retry-After` header
+    pub retry_after: Option<u64>,
+}
+
+impl StatusError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        StatusError {
+            status,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        StatusError::new(StatusCode::BAD_REQUEST, message)
+    }
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
@@ -0,0 +1,152 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+};
+use chrono::{DateTime, Duration, ParseError, Utc};
+
+use crate::{
+    cch_error::{ReportError, StatusError},
+    ServerState,
+};
+
+/// Where the server gets the current time from
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Moves the clock on, only clocks made for testing can be moved
+    fn advance(&self, _by: Duration) -> Result<(), StatusError> {
+        Err(StatusError::new(
+            StatusCode::CONFLICT,
+            "The clock can't be adjusted",
+        ))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it is advanced
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+
+    fn advance(&self, by: Duration) -> Result<(), StatusError> {
+        let mut now = self.now.lock().unwrap();
+        *now = now
+            .checked_add_signed(by)
+            .ok_or_else(|| StatusError::bad_request("The clock can't go that far"))?;
+        Ok(())
+    }
+}
+
+/// The system clock, or a manual one starting at `start` when it is given.
+/// `start` is an RFC 3339 time or `now`.
+pub fn clock(start: Option<String>) -> Result<Arc<dyn Clock>, ParseError> {
+    let start = match start.as_deref() {
+        None => return Ok(Arc::new(SystemClock)),
+        Some("now") => Utc::now(),
+        Some(start) => DateTime::parse_from_rfc3339(start)?.with_timezone(&Utc),
+    };
+    Ok(Arc::new(ManualClock::new(start)))
+}
+
+/// Moves a manual clock on, this takes the same bearer token as asset changes
+#[tracing::instrument(skip(state, headers))]
+pub async fn advance_clock(
+    State(state): State<ServerState>,
+    Path(seconds): Path<i64>,
+    headers: HeaderMap,
+) -> Result<String, ReportError> {
+    state.assets.authorize(&headers)?;
+    if seconds.unsigned_abs() > Duration::max_value().num_seconds() as u64 {
+        return Err(StatusError::bad_request("The clock can't go that far").into());
+    }
+    state.clock.advance(Duration::seconds(seconds))?;
+    Ok(state.clock.now().to_rfc3339())
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn current_time(State(state): State<ServerState>) -> String {
+    state.clock.now().to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::AUTHORIZATION;
+
+    use crate::assets::AssetStore;
+
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_advanced() {
+        let start = DateTime::parse_from_rfc3339("2023-12-24T00:00:00Z").unwrap();
+        let clock = clock(Some(start.to_rfc3339())).unwrap();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::seconds(90)).unwrap();
+        assert_eq!(clock.now(), start + Duration::seconds(90));
+        assert!(clock.advance(Duration::max_value()).is_err());
+        assert_eq!(clock.now(), start + Duration::seconds(90));
+    }
+
+    #[test]
+    fn system_clock_can_not_be_advanced() {
+        let err = clock(None)
+            .unwrap()
+            .advance(Duration::seconds(1))
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn invalid_start_times_fail() {
+        assert!(clock(Some("yesterday".to_string())).is_err());
+        assert!(clock(Some("now".to_string())).is_ok());
+    }
+
+    #[tokio::test]
+    async fn advancing_needs_the_admin_token() {
+        let start = DateTime::parse_from_rfc3339("2023-12-24T00:00:00Z").unwrap();
+        let mut state = ServerState::for_tests(Arc::new(ManualClock::new(start.into())));
+        state.assets = AssetStore::new("assets", Some("token".to_string()));
+        let advance = |headers| advance_clock(State(state.clone()), Path(60), headers);
+
+        let Err(err) = advance(HeaderMap::new()).await else {
+            panic!("the clock moved without a token");
+        };
+        assert_eq!(err.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(state.clock.now(), start);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer token".parse().unwrap());
+        let Ok(now) = advance(headers).await else {
+            panic!("the token was refused");
+        };
+        assert_eq!(now, (start + Duration::seconds(60)).to_rfc3339());
+    }
+}
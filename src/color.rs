@@ -0,0 +1,476 @@
+use image::{Rgba, RgbaImage};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::cch_error::StatusError;
+
+const MAX_CLUSTERS: usize = 16;
+/// pixels k-means looks at, larger images are sampled evenly
+const KMEANS_SAMPLE: usize = 20_000;
+const KMEANS_ROUNDS: usize = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    R,
+    G,
+    B,
+}
+
+/// Which pixels are counted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorRule {
+    /// red is brighter than green and blue together
+    MagicRed,
+    /// `channel` is brighter than both other channels
+    Dominant(Channel),
+    /// hue in degrees within `min..=max`, wrapping past 360 when `min > max`
+    Hue {
+        min: f64,
+        max: f64,
+        min_saturation: f64,
+        min_value: f64,
+    },
+    /// within `tolerance` of `target` in RGB space
+    Distance { target: [u8; 3], tolerance: f64 },
+}
+
+impl ColorRule {
+    pub fn matches(&self, [r, g, b, _]: [u8; 4]) -> bool {
+        match *self {
+            ColorRule::MagicRed => r > b.saturating_add(g),
+            ColorRule::Dominant(Channel::R) => r > g && r > b,
+            ColorRule::Dominant(Channel::G) => g > r && g > b,
+            ColorRule::Dominant(Channel::B) => b > r && b > g,
+            ColorRule::Hue {
+                min,
+                max,
+                min_saturation,
+                min_value,
+            } => {
+                let (hue, saturation, value) = hsv([r, g, b]);
+                let in_range = if min <= max {
+                    (min..=max).contains(&hue)
+                } else {
+                    hue >= min || hue <= max
+                };
+                in_range && saturation >= min_saturation && value >= min_value
+            }
+            ColorRule::Distance { target, tolerance } => {
+                distance([r, g, b].map(f64::from), target.map(f64::from)) <= tolerance
+            }
+        }
+    }
+}
+
+/// Hue in degrees, saturation and value from 0 to 1
+fn hsv([r, g, b]: [u8; 3]) -> (f64, f64, f64) {
+    let [r, g, b] = [r, g, b].map(|c| f64::from(c) / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Parses `#rrggbb` or `rrggbb`
+pub fn parse_hex(color: &str) -> Result<[u8; 3], StatusError> {
+    let hex = color.trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+    };
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(StatusError::bad_request(format!(
+            "{color} isn't a #rrggbb color"
+        ))),
+    }
+}
+
+fn to_hex([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+/// Pixel counts for every value of each channel
+#[derive(Debug, Serialize)]
+pub struct Histogram {
+    r: Vec<u64>,
+    g: Vec<u64>,
+    b: Vec<u64>,
+    a: Vec<u64>,
+}
+
+pub fn histogram<'a>(pixels: impl Iterator<Item = &'a Rgba<u8>>) -> Histogram {
+    let mut histogram = Histogram {
+        r: vec![0; 256],
+        g: vec![0; 256],
+        b: vec![0; 256],
+        a: vec![0; 256],
+    };
+    for Rgba([r, g, b, a]) in pixels {
+        histogram.r[*r as usize] += 1;
+        histogram.g[*g as usize] += 1;
+        histogram.b[*b as usize] += 1;
+        histogram.a[*a as usize] += 1;
+    }
+    histogram
+}
+
+#[derive(Debug, Serialize)]
+pub struct DominantColor {
+    color: String,
+    /// part of the pixels closest to this color
+    share: f64,
+}
+
+/// The `k` dominant colors of `pixels` found by k-means, most common first.
+/// Seeding is fixed so the same image always gives the same colors.
+pub fn dominant_colors(pixels: &[[u8; 3]], k: usize) -> Result<Vec<DominantColor>, StatusError> {
+    if !(1..=MAX_CLUSTERS).contains(&k) {
+        return Err(StatusError::bad_request(format!(
+            "colors must be between 1 and {MAX_CLUSTERS}"
+        )));
+    }
+    if pixels.is_empty() {
+        return Ok(vec![]);
+    }
+    let step = (pixels.len() / KMEANS_SAMPLE).max(1);
+    let sample: Vec<[f64; 3]> = pixels
+        .iter()
+        .step_by(step)
+        .map(|p| p.map(f64::from))
+        .collect();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut centroids = plus_plus_seeds(&sample, k, &mut rng);
+
+    let mut assignments = vec![0; sample.len()];
+    for _ in 0..KMEANS_ROUNDS {
+        let mut changed = false;
+        for (pixel, assigned) in sample.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest(&centroids, *pixel);
+            changed |= nearest != *assigned;
+            *assigned = nearest;
+        }
+        let mut sums = vec![([0.0; 3], 0usize); centroids.len()];
+        for (pixel, &assigned) in sample.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[assigned];
+            for c in 0..3 {
+                sum[c] += pixel[c];
+            }
+            *count += 1;
+        }
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            if count > 0 {
+                *centroid = sum.map(|s| s / count as f64);
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut counts = vec![0usize; centroids.len()];
+    for &assigned in &assignments {
+        counts[assigned] += 1;
+    }
+    let mut colors: Vec<DominantColor> = centroids
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count > 0)
+        .map(|(centroid, count)| DominantColor {
+            color: to_hex(centroid.map(|c| c.round() as u8)),
+            share: count as f64 / sample.len() as f64,
+        })
+        .collect();
+    colors.sort_by(|a, b| b.share.total_cmp(&a.share));
+    Ok(colors)
+}
+
+fn nearest(centroids: &[[f64; 3]], pixel: [f64; 3]) -> usize {
+    centroids
+        .iter()
+        .map(|centroid| distance(*centroid, pixel))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(0, |(index, _)| index)
+}
+
+/// k-means++ seeding, later seeds are likely to be far from earlier ones
+fn plus_plus_seeds(sample: &[[f64; 3]], k: usize, rng: &mut StdRng) -> Vec<[f64; 3]> {
+    let mut centroids = vec![sample[rng.gen_range(0..sample.len())]];
+    while centroids.len() < k {
+        let weights: Vec<f64> = sample
+            .iter()
+            .map(|pixel| {
+                let d = distance(centroids[nearest(&centroids, *pixel)], *pixel);
+                d * d
+            })
+            .collect();
+        let total: f64 = weights.iter().sum();
+        if total == 0.0 {
+            // fewer distinct colors than clusters
+            break;
+        }
+        let mut target = rng.gen_range(0.0..total);
+        let chosen = weights
+            .iter()
+            .position(|w| {
+                target -= w;
+                target < 0.0
+            })
+            .unwrap_or(sample.len() - 1);
+        centroids.push(sample[chosen]);
+    }
+    centroids
+}
+
+/// How the pixels that don't match are shown in a mask
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskStyle {
+    /// darkened and without color
+    Dim,
+    Transparent,
+}
+
+/// Box drawn around a region of matching pixels
+const BOX_COLOR: Rgba<u8> = Rgba([0, 255, 255, 255]);
+
+/// Bounding boxes `(min_x, min_y, max_x, max_y)` of the 8-connected regions
+/// of `matching` with at least `min_size` pixels
+fn regions(matching: &[bool], width: u32, height: u32, min_size: usize) -> Vec<[u32; 4]> {
+    let (w, h) = (width as usize, height as usize);
+    let mut seen = vec![false; matching.len()];
+    let mut boxes = vec![];
+    let mut stack = vec![];
+    for start in 0..matching.len() {
+        if !matching[start] || seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        stack.push(start);
+        let mut size = 0;
+        let mut bounds = [u32::MAX, u32::MAX, 0, 0];
+        while let Some(index) = stack.pop() {
+            size += 1;
+            let (x, y) = (index % w, index / w);
+            bounds = [
+                bounds[0].min(x as u32),
+                bounds[1].min(y as u32),
+                bounds[2].max(x as u32),
+                bounds[3].max(y as u32),
+            ];
+            for ny in y.saturating_sub(1)..=(y + 1).min(h - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(w - 1) {
+                    let next = ny * w + nx;
+                    if matching[next] && !seen[next] {
+                        seen[next] = true;
+                        stack.push(next);
+                    }
+                }
+            }
+        }
+        if size >= min_size {
+            boxes.push(bounds);
+        }
+    }
+    boxes
+}
+
+/// A copy of `image` with the pixels `matches` rejects dimmed or hidden and
+/// boxes around the regions it accepts
+pub fn render_mask(
+    image: &RgbaImage,
+    matches: impl Fn(&Rgba<u8>) -> bool,
+    style: MaskStyle,
+    min_region: usize,
+) -> RgbaImage {
+    let matching: Vec<bool> = image.pixels().map(&matches).collect();
+    let mut mask = image.clone();
+    for (pixel, &keep) in mask.pixels_mut().zip(&matching) {
+        if keep {
+            continue;
+        }
+        let Rgba([r, g, b, a]) = *pixel;
+        *pixel = match style {
+            MaskStyle::Dim => {
+                let luma =
+                    (0.299 * f64::from(r) + 0.587 * f64::from(g) + 0.114 * f64::from(b)) / 4.0;
+                let luma = luma.round() as u8;
+                Rgba([luma, luma, luma, a])
+            }
+            MaskStyle::Transparent => Rgba([r, g, b, 0]),
+        };
+    }
+    for [min_x, min_y, max_x, max_y] in
+        regions(&matching, image.width(), image.height(), min_region)
+    {
+        for x in min_x..=max_x {
+            mask.put_pixel(x, min_y, BOX_COLOR);
+            mask.put_pixel(x, max_y, BOX_COLOR);
+        }
+        for y in min_y..=max_y {
+            mask.put_pixel(min_x, y, BOX_COLOR);
+            mask.put_pixel(max_x, y, BOX_COLOR);
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [200, 20, 20, 255];
+    const MAGENTA: [u8; 4] = [230, 10, 120, 255];
+    const GREEN: [u8; 4] = [20, 200, 20, 255];
+
+    #[test]
+    fn hue_ranges_wrap_past_360() {
+        let reds = ColorRule::Hue {
+            min: 330.0,
+            max: 20.0,
+            min_saturation: 0.5,
+            min_value: 0.2,
+        };
+        assert!(reds.matches(RED));
+        assert!(reds.matches(MAGENTA));
+        assert!(!reds.matches(GREEN));
+        // grey has no saturation whatever its hue
+        assert!(!reds.matches([128, 128, 128, 255]));
+
+        let greens = ColorRule::Hue {
+            min: 90.0,
+            max: 150.0,
+            min_saturation: 0.0,
+            min_value: 0.0,
+        };
+        assert!(greens.matches(GREEN));
+        assert!(!greens.matches(MAGENTA));
+    }
+
+    #[test]
+    fn hsv_covers_every_sector() {
+        assert_eq!(hsv([255, 0, 0]), (0.0, 1.0, 1.0));
+        assert_eq!(hsv([0, 255, 0]).0, 120.0);
+        assert_eq!(hsv([0, 0, 255]).0, 240.0);
+        assert_eq!(hsv([255, 0, 255]).0, 300.0);
+        assert_eq!(hsv([0, 0, 0]), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn dominant_channels_must_beat_both_others() {
+        assert!(ColorRule::Dominant(Channel::R).matches(RED));
+        assert!(ColorRule::Dominant(Channel::G).matches(GREEN));
+        assert!(!ColorRule::Dominant(Channel::B).matches(GREEN));
+        // a tie isn't dominance
+        assert!(!ColorRule::Dominant(Channel::R).matches([100, 100, 0, 255]));
+        // magic red needs more red than green and blue together
+        assert!(ColorRule::Dominant(Channel::R).matches([120, 60, 70, 255]));
+        assert!(!ColorRule::MagicRed.matches([120, 60, 70, 255]));
+        assert!(ColorRule::MagicRed.matches(RED));
+    }
+
+    #[test]
+    fn distance_is_inclusive() {
+        let rule = ColorRule::Distance {
+            target: [200, 20, 20],
+            tolerance: 5.0,
+        };
+        assert!(rule.matches(RED));
+        assert!(rule.matches([203, 24, 20, 0]));
+        assert!(!rule.matches([204, 24, 20, 255]));
+    }
+
+    #[test]
+    fn hex_colors_round_trip() {
+        assert_eq!(parse_hex("#c81414").unwrap(), [200, 20, 20]);
+        assert_eq!(parse_hex("C81414").unwrap(), [200, 20, 20]);
+        assert_eq!(to_hex([200, 20, 20]), "#c81414");
+        for bad in ["#c8141", "#c814140", "#g81414", "", "#éé14"] {
+            let err = parse_hex(bad).unwrap_err();
+            assert_eq!(err.status, axum::http::StatusCode::BAD_REQUEST, "{bad}");
+        }
+    }
+
+    #[test]
+    fn kmeans_finds_the_main_colors() {
+        let pixels: Vec<[u8; 3]> = [[250, 0, 0]; 60]
+            .into_iter()
+            .chain([[0, 0, 250]; 30])
+            .chain([[0, 250, 0]; 10])
+            .collect();
+        let colors = dominant_colors(&pixels, 3).unwrap();
+        let found: Vec<(&str, f64)> = colors.iter().map(|c| (c.color.as_str(), c.share)).collect();
+        assert_eq!(
+            found,
+            [("#fa0000", 0.6), ("#0000fa", 0.3), ("#00fa00", 0.1)]
+        );
+
+        // fewer colors than clusters leaves the others out
+        let colors = dominant_colors(&[[1, 2, 3]; 5], 4).unwrap();
+        assert_eq!(colors.len(), 1);
+        assert_eq!(colors[0].color, "#010203");
+        assert!(dominant_colors(&[], 3).unwrap().is_empty());
+        assert!(dominant_colors(&pixels, 0).is_err());
+        assert!(dominant_colors(&pixels, MAX_CLUSTERS + 1).is_err());
+    }
+
+    /// a 6x4 image with a 2x2 red square, a diagonal red pair and one stray red pixel
+    fn two_regions() -> RgbaImage {
+        let red = [(0, 0), (1, 0), (0, 1), (1, 1), (4, 1), (5, 2), (3, 3)];
+        RgbaImage::from_fn(6, 4, |x, y| {
+            if red.contains(&(x, y)) {
+                Rgba(RED)
+            } else {
+                Rgba(GREEN)
+            }
+        })
+    }
+
+    #[test]
+    fn regions_are_8_connected() {
+        let image = two_regions();
+        let matching: Vec<bool> = image.pixels().map(|p| p.0 == RED).collect();
+        assert_eq!(
+            regions(&matching, 6, 4, 1),
+            [[0, 0, 1, 1], [4, 1, 5, 2], [3, 3, 3, 3]]
+        );
+        assert_eq!(regions(&matching, 6, 4, 2), [[0, 0, 1, 1], [4, 1, 5, 2]]);
+        assert!(regions(&matching, 6, 4, 5).is_empty());
+    }
+
+    #[test]
+    fn masks_box_the_regions() {
+        let image = two_regions();
+        let is_red = |p: &Rgba<u8>| p.0 == RED;
+        let mask = render_mask(&image, is_red, MaskStyle::Transparent, 2);
+        for (x, y) in [(0, 0), (1, 1), (4, 1), (5, 2), (4, 2), (5, 1)] {
+            assert_eq!(*mask.get_pixel(x, y), BOX_COLOR, "({x}, {y})");
+        }
+        // outside every box the rejected pixels are hidden, the stray one is kept
+        assert_eq!(mask.get_pixel(2, 2).0, [20, 200, 20, 0]);
+        assert_eq!(*mask.get_pixel(3, 3), Rgba(RED));
+
+        let mask = render_mask(&image, is_red, MaskStyle::Dim, 5);
+        assert_eq!(mask.get_pixel(2, 2).0, [31, 31, 31, 255]);
+        assert_eq!(*mask.get_pixel(0, 0), Rgba(RED));
+    }
+}
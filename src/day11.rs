@@ -1,27 +1,646 @@
-use std::io::Cursor;
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, ErrorKind},
+    path::Path as FsPath,
+};
 
-use axum::{extract::Multipart, response::IntoResponse};
-use image::{io::Reader as ImageReader, ImageFormat};
+use axum::{
+    extract::{Multipart, Path, Query, State},
+    http::{header::CONTENT_TYPE, HeaderName},
+    response::{IntoResponse, Response},
+    Json,
+};
+use color_eyre::{eyre::WrapErr, Report};
+use exif::{In, Tag, Value};
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder},
+    io::Reader as ImageReader,
+    ColorType, DynamicImage, ImageDecoder, ImageFormat, RgbaImage,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
 
+use crate::{
+    assets::read_asset,
+    cch_error::{ReportError, StatusError},
+    color::{
+        dominant_colors, histogram, parse_hex, render_mask, Channel, ColorRule, DominantColor,
+        Histogram, MaskStyle,
+    },
+    day21::dms_string,
+    transform::{Chain, OutputFormat},
+    ServerState,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    #[default]
+    MagicRed,
+    Dominant,
+    Hue,
+    Distance,
+}
+
+/// Taken from the query string, or from a JSON multipart part named `options`
+#[derive(Debug, Deserialize)]
+pub struct PixelOptions {
+    /// pixels less opaque than this are not counted, by default only fully
+    /// transparent pixels are skipped
+    #[serde(default = "default_min_alpha")]
+    min_alpha: u8,
+    /// answer with the list of parts even for a single image
+    #[serde(default)]
+    detailed: bool,
+    #[serde(default)]
+    rule: RuleKind,
+    /// for the `dominant` rule
+    channel: Option<Channel>,
+    /// for the `hue` rule, in degrees
+    hue_min: Option<f64>,
+    hue_max: Option<f64>,
+    min_saturation: Option<f64>,
+    min_value: Option<f64>,
+    /// for the `distance` rule, a `#rrggbb` color
+    target: Option<String>,
+    tolerance: Option<f64>,
+    /// include per channel histograms
+    #[serde(default)]
+    histogram: bool,
+    /// include this many dominant colors
+    colors: Option<usize>,
+    /// answer with a PNG of the matching pixels instead of counts
+    mask: Option<MaskStyle>,
+    /// smallest region of matching pixels that gets a box in the mask
+    #[serde(default = "default_min_region")]
+    min_region: usize,
+}
+
+fn default_min_region() -> usize {
+    16
+}
+
+impl PixelOptions {
+    fn color_rule(&self) -> Result<ColorRule, StatusError> {
+        let missing = |name: &str| StatusError::bad_request(format!("The rule needs {name}"));
+        Ok(match self.rule {
+            RuleKind::MagicRed => ColorRule::MagicRed,
+            RuleKind::Dominant => {
+                ColorRule::Dominant(self.channel.ok_or_else(|| missing("channel"))?)
+            }
+            RuleKind::Hue => ColorRule::Hue {
+                min: self.hue_min.ok_or_else(|| missing("hue_min"))?,
+                max: self.hue_max.ok_or_else(|| missing("hue_max"))?,
+                min_saturation: self.min_saturation.unwrap_or(0.0),
+                min_value: self.min_value.unwrap_or(0.0),
+            },
+            RuleKind::Distance => ColorRule::Distance {
+                target: parse_hex(self.target.as_deref().ok_or_else(|| missing("target"))?)?,
+                tolerance: self.tolerance.ok_or_else(|| missing("tolerance"))?,
+            },
+        })
+    }
+
+    /// whether anything beyond the plain count was asked for
+    fn wants_report(&self) -> bool {
+        self.detailed || self.histogram || self.colors.is_some()
+    }
+}
+
+/// The counts for one image of the upload
+#[derive(Debug, Serialize)]
+pub struct PartResult {
+    field_name: Option<String>,
+    file_name: Option<String>,
+    width: u32,
+    height: u32,
+    magic_red_count: usize,
+    /// pixels matching the requested rule
+    matching_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    histogram: Option<Histogram>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dominant_colors: Option<Vec<DominantColor>>,
+}
+
+fn default_min_alpha() -> u8 {
+    1
+}
+
+/// The format of an image, found from its content
+fn guess_format(data: &[u8]) -> Result<ImageFormat, StatusError> {
+    image::guess_format(data).map_err(|_| StatusError::bad_request("Unknown image format"))
+}
+
+/// Decodes an image of any supported format, found from its content
+fn decode(data: &[u8]) -> Result<DynamicImage, Report> {
+    let reader = ImageReader::with_format(Cursor::new(data), guess_format(data)?);
+    reader
+        .decode()
+        .wrap_err(StatusError::bad_request("Can't decode the image"))
+}
+
+/// Decodes one uploaded part, naming the part when it isn't an image
+fn decode_part(field_name: Option<&str>, data: &[u8]) -> Result<RgbaImage, Report> {
+    let part = field_name.unwrap_or("unnamed");
+    Ok(decode(data)
+        .wrap_err(StatusError::bad_request(format!(
+            "Part {part} isn't a valid image"
+        )))?
+        .to_rgba8())
+}
+
+fn analyze(
+    field_name: Option<String>,
+    file_name: Option<String>,
+    image: &RgbaImage,
+    options: &PixelOptions,
+    rule: ColorRule,
+) -> Result<PartResult, StatusError> {
+    let visible = || image.pixels().filter(|p| p[3] >= options.min_alpha);
+    let count = |rule: ColorRule| visible().filter(|p| rule.matches(p.0)).count();
+    let dominant_colors = match options.colors {
+        Some(k) => {
+            let pixels: Vec<[u8; 3]> = visible().map(|p| [p[0], p[1], p[2]]).collect();
+            Some(dominant_colors(&pixels, k)?)
+        }
+        None => None,
+    };
+    Ok(PartResult {
+        field_name,
+        file_name,
+        width: image.width(),
+        height: image.height(),
+        magic_red_count: count(ColorRule::MagicRed),
+        matching_count: count(rule),
+        histogram: options.histogram.then(|| histogram(visible())),
+        dominant_colors,
+    })
+}
+
+/// Every part of the upload is decoded as its own image, except a part named
+/// `options` which replaces the query options. A single image is answered
+/// with just its count unless more was asked for, otherwise with a list of
+/// `PartResult`.
 #[axum::debug_handler]
-#[tracing::instrument]
-pub async fn num_red_pixels(mut multipart: Multipart) -> impl IntoResponse {
-    let mut data = vec![];
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        data.append(&mut field.bytes().await.unwrap().to_vec());
-    }
-    let reader = ImageReader::with_format(Cursor::new(data), ImageFormat::Png);
-    let decoded = reader.decode().unwrap();
-    let num_magic_red = decoded
-        .as_rgb8()
-        .unwrap()
-        .pixels()
-        .filter(|p| {
-            let r = p[0];
-            let g = p[1];
-            let b = p[2];
-            r > b.saturating_add(g)
+#[tracing::instrument(skip(multipart))]
+pub async fn num_red_pixels(
+    Query(mut options): Query<PixelOptions>,
+    mut multipart: Multipart,
+) -> Result<Response, ReportError> {
+    // kept encoded, `options` may still come after the images
+    let mut images = vec![];
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .wrap_err(StatusError::bad_request("Invalid multipart body"))?
+    {
+        let field_name = field.name().map(String::from);
+        let file_name = field.file_name().map(String::from);
+        let is_options = field_name.as_deref() == Some("options") && file_name.is_none();
+        // only file fields hold images
+        if file_name.is_none() && !is_options {
+            continue;
+        }
+        let data = field
+            .bytes()
+            .await
+            .wrap_err(StatusError::bad_request("Invalid multipart body"))?;
+        if is_options {
+            options = serde_json::from_slice(&data)
+                .wrap_err(StatusError::bad_request("Invalid options part"))?;
+            continue;
+        }
+        images.push((field_name, file_name, data));
+    }
+    let rule = options.color_rule()?;
+    if let Some(style) = options.mask {
+        let [(field_name, _, data)] = <[_; 1]>::try_from(images)
+            .map_err(|_| StatusError::bad_request("A mask takes exactly one image"))?;
+        let min_alpha = options.min_alpha;
+        let min_region = options.min_region.max(1);
+        // decoding and drawing are CPU bound, like the transforms
+        let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, Report> {
+            let image = decode_part(field_name.as_deref(), &data)?;
+            let mask = render_mask(
+                &image,
+                |p| p[3] >= min_alpha && rule.matches(p.0),
+                style,
+                min_region,
+            );
+            let mut png = Cursor::new(vec![]);
+            mask.write_to(&mut png, ImageFormat::Png)?;
+            Ok(png.into_inner())
+        })
+        .await??;
+        return Ok(([(CONTENT_TYPE, "image/png")], png).into_response());
+    }
+    let wants_report = options.wants_report();
+    // decoded one at a time so only one image is in memory
+    let parts = tokio::task::spawn_blocking(move || {
+        images
+            .into_iter()
+            .map(|(field_name, file_name, data)| {
+                let image = decode_part(field_name.as_deref(), &data)?;
+                Ok(analyze(field_name, file_name, &image, &options, rule)?)
+            })
+            .collect::<Result<Vec<_>, Report>>()
+    })
+    .await??;
+    match parts.as_slice() {
+        [] => Err(StatusError::bad_request("No image in the body").into()),
+        [part] if !wants_report => Ok(part.matching_count.to_string().into_response()),
+        _ => Ok(Json(parts).into_response()),
+    }
+}
+
+/// The transform cache is trimmed to this after every miss, oldest results
+/// first
+const MAX_TRANSFORM_CACHE_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub struct TransformQuery {
+    /// transforms separated by commas, like `crop:0:0:100:100,rotate:90`
+    #[serde(default)]
+    ops: String,
+    /// defaults to the input's format
+    format: Option<OutputFormat>,
+}
+
+/// Runs the chain on `data`, results are kept in the cache dir under a hash
+/// of the input, the chain and the output format
+async fn transform_image(
+    cache_dir: &FsPath,
+    data: Vec<u8>,
+    query: &TransformQuery,
+) -> Result<Response, ReportError> {
+    let chain: Chain = query.ops.parse()?;
+    let format = query
+        .format
+        .unwrap_or_else(|| OutputFormat::from_input(image::guess_format(&data).ok()));
+    let mut hasher = Sha256::new();
+    hasher.update(&data);
+    hasher.update(format!("{chain:?} {format:?}"));
+    let path = cache_dir.join(format!("{:x}.{}", hasher.finalize(), format.extension()));
+
+    let headers = |cache: &'static str| {
+        [
+            (CONTENT_TYPE, format.mime_type()),
+            (HeaderName::from_static("x-cache"), cache),
+        ]
+    };
+    match tokio::fs::read(&path).await {
+        Ok(cached) => return Ok((headers("hit"), cached).into_response()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    let encoded = tokio::task::spawn_blocking(move || chain.run(decode(&data)?, format)).await??;
+    tokio::fs::create_dir_all(cache_dir).await?;
+    // written aside first so a concurrent reader never sees half a file
+    let partial = cache_dir.join(format!(
+        ".{}.{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        ulid::Ulid::new()
+    ));
+    tokio::fs::write(&partial, &encoded).await?;
+    tokio::fs::rename(&partial, &path).await?;
+    if let Err(err) = trim_cache(cache_dir, MAX_TRANSFORM_CACHE_BYTES).await {
+        warn!(?err, "can't trim the transform cache");
+    }
+    Ok((headers("miss"), encoded).into_response())
+}
+
+/// Removes the oldest cached results until the cache fits in `max_bytes`.
+/// Files still being written start with a dot and are left alone.
+async fn trim_cache(cache_dir: &FsPath, max_bytes: u64) -> Result<(), std::io::Error> {
+    let mut entries = vec![];
+    let mut dir = tokio::fs::read_dir(cache_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // another request may have removed it already
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        if metadata.is_file() {
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+    }
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    entries.sort();
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        total -= len;
+    }
+    Ok(())
+}
+
+/// Transforms the first file part of the upload
+#[tracing::instrument(skip(state, multipart))]
+pub async fn transform_upload(
+    State(state): State<ServerState>,
+    Query(query): Query<TransformQuery>,
+    mut multipart: Multipart,
+) -> Result<Response, ReportError> {
+    let field = loop {
+        let field = multipart
+            .next_field()
+            .await
+            .wrap_err(StatusError::bad_request("Invalid multipart body"))?
+            .ok_or_else(|| StatusError::bad_request("No image in the body"))?;
+        // only file fields hold images
+        if field.file_name().is_some() {
+            break field;
+        }
+    };
+    let data = field
+        .bytes()
+        .await
+        .wrap_err(StatusError::bad_request("Invalid multipart body"))?;
+    transform_image(&state.transform_cache, data.to_vec(), &query).await
+}
+
+/// Transforms one of the served assets
+#[tracing::instrument(skip(state))]
+pub async fn transform_asset(
+    State(state): State<ServerState>,
+    Path(name): Path<String>,
+    Query(query): Query<TransformQuery>,
+) -> Result<Response, ReportError> {
+    let data = read_asset(&state, &name).await?;
+    transform_image(&state.transform_cache, data, &query).await
+}
+
+#[derive(Debug, Serialize)]
+pub struct GpsPosition {
+    latitude: f64,
+    longitude: f64,
+    /// in the format of `/21/coords`
+    dms: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExifSummary {
+    make: Option<String>,
+    model: Option<String>,
+    orientation: Option<u32>,
+    taken_at: Option<String>,
+    gps: Option<GpsPosition>,
+    /// every tag as displayed by the exif reader
+    tags: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageMetadata {
+    field_name: Option<String>,
+    file_name: Option<String>,
+    format: String,
+    width: u32,
+    height: u32,
+    color_type: String,
+    channels: u8,
+    /// bits per channel
+    bit_depth: u16,
+    has_alpha: bool,
+    icc_profile: bool,
+    exif: Option<ExifSummary>,
+}
+
+/// Dimensions, color type and whether there is an ICC profile, read from the
+/// headers for the formats that expose a profile
+fn inspect_decoder<'a>(mut decoder: impl ImageDecoder<'a>) -> ((u32, u32), ColorType, bool) {
+    let icc_profile = decoder.icc_profile().is_some();
+    (decoder.dimensions(), decoder.color_type(), icc_profile)
+}
+
+fn inspect(data: &[u8], format: ImageFormat) -> Result<((u32, u32), ColorType, bool), Report> {
+    let cursor = Cursor::new(data);
+    let inspected = match format {
+        ImageFormat::Png => inspect_decoder(PngDecoder::new(cursor)?),
+        ImageFormat::Jpeg => inspect_decoder(JpegDecoder::new(cursor)?),
+        ImageFormat::WebP => inspect_decoder(WebPDecoder::new(cursor)?),
+        ImageFormat::Tiff => inspect_decoder(TiffDecoder::new(cursor)?),
+        _ => {
+            let image = decode(data)?;
+            ((image.width(), image.height()), image.color(), false)
+        }
+    };
+    Ok(inspected)
+}
+
+fn exif_text(exif: &exif::Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|text| String::from_utf8_lossy(text).trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Signed decimal degrees from a GPS degrees, minutes, seconds tag and its reference
+fn exif_degrees(exif: &exif::Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let Value::Rational(parts) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let degrees = parts
+        .iter()
+        .zip([1.0, 60.0, 3600.0])
+        .map(|(part, scale)| part.to_f64() / scale)
+        .sum::<f64>();
+    let negative = exif_text(exif, reference).is_some_and(|r| r.eq_ignore_ascii_case(negative));
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// EXIF of the formats that can carry it, other formats have none
+fn exif_summary(data: &[u8], format: ImageFormat) -> Result<Option<ExifSummary>, Report> {
+    let carries_exif = matches!(
+        format,
+        ImageFormat::Tiff | ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP
+    );
+    if !carries_exif {
+        return Ok(None);
+    }
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_) | exif::Error::BlankValue(_)) => return Ok(None),
+        Err(err) => return Err(err).wrap_err(StatusError::bad_request("Invalid EXIF data")),
+    };
+    // positions that can't be a cell on the globe are left out
+    let gps = match (
+        exif_degrees(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        exif_degrees(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+    ) {
+        (Some(latitude), Some(longitude)) => {
+            dms_string(latitude, longitude).ok().map(|dms| GpsPosition {
+                latitude,
+                longitude,
+                dms,
+            })
+        }
+        _ => None,
+    };
+    let tags = exif
+        .fields()
+        .map(|field| {
+            let value = field.display_value().with_unit(&exif).to_string();
+            (field.tag.to_string(), value)
         })
-        .count();
-    num_magic_red.to_string()
+        .collect();
+    Ok(Some(ExifSummary {
+        make: exif_text(&exif, Tag::Make),
+        model: exif_text(&exif, Tag::Model),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0)),
+        taken_at: exif_text(&exif, Tag::DateTimeOriginal),
+        gps,
+        tags,
+    }))
+}
+
+fn image_metadata(
+    field_name: Option<String>,
+    file_name: Option<String>,
+    data: &[u8],
+) -> Result<ImageMetadata, Report> {
+    let format = guess_format(data)?;
+    let ((width, height), color_type, icc_profile) =
+        inspect(data, format).wrap_err(StatusError::bad_request("Can't read the image"))?;
+    let channels = color_type.channel_count();
+    Ok(ImageMetadata {
+        field_name,
+        file_name,
+        format: format.extensions_str()[0].to_string(),
+        width,
+        height,
+        color_type: format!("{color_type:?}"),
+        channels,
+        bit_depth: color_type.bits_per_pixel() / u16::from(channels),
+        has_alpha: color_type.has_alpha(),
+        icc_profile,
+        exif: exif_summary(data, format)?,
+    })
+}
+
+/// Metadata for every part of an upload like the one `/11/red_pixels` takes
+#[tracing::instrument(skip(multipart))]
+pub async fn image_metadata_list(
+    mut multipart: Multipart,
+) -> Result<Json<Vec<ImageMetadata>>, ReportError> {
+    let mut parts = vec![];
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .wrap_err(StatusError::bad_request("Invalid multipart body"))?
+    {
+        let field_name = field.name().map(String::from);
+        let file_name = field.file_name().map(String::from);
+        // only file fields hold images
+        if file_name.is_none() {
+            continue;
+        }
+        let data = field
+            .bytes()
+            .await
+            .wrap_err(StatusError::bad_request("Invalid multipart body"))?;
+        let part = field_name.clone().unwrap_or_else(|| "unnamed".to_string());
+        let metadata = image_metadata(field_name, file_name, &data).wrap_err(
+            StatusError::bad_request(format!("Part {part} isn't a valid image")),
+        )?;
+        parts.push(metadata);
+    }
+    if parts.is_empty() {
+        return Err(StatusError::bad_request("No image in the body").into());
+    }
+    Ok(Json(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, extract::FromRequest, http::Request};
+
+    use crate::clock;
+
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        RgbaImage::new(width, height)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    async fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> Multipart {
+        let mut body = vec![];
+        for (name, file_name, data) in parts {
+            body.extend(b"--boundary\r\n");
+            let file_name = file_name.map_or(String::new(), |f| format!("; filename=\"{f}\""));
+            body.extend(
+                format!("Content-Disposition: form-data; name=\"{name}\"{file_name}\r\n\r\n")
+                    .bytes(),
+            );
+            body.extend(*data);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--boundary--\r\n");
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(req, &()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_skip_text_fields() {
+        let cache = tempfile::tempdir().unwrap();
+        let mut state = ServerState::for_tests(clock::clock(None).unwrap());
+        state.transform_cache = cache.path().to_path_buf();
+        let query = TransformQuery {
+            ops: "rotate:90".to_string(),
+            format: None,
+        };
+        let parts = multipart(&[("note", None, b"hi"), ("image", Some("a.png"), &png(3, 2))]).await;
+        let Ok(response) = transform_upload(State(state), Query(query), parts).await else {
+            panic!("the image part wasn't found");
+        };
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let image = decode(&body).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 3));
+    }
+
+    #[tokio::test]
+    async fn cache_drops_the_oldest_results() {
+        let cache = tempfile::tempdir().unwrap();
+        for name in ["a.png", "b.png", "c.png"] {
+            tokio::fs::write(cache.path().join(name), [0; 10])
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::fs::write(cache.path().join(".d.png.partial"), [0; 10])
+            .await
+            .unwrap();
+        trim_cache(cache.path(), 20).await.unwrap();
+        let mut left = vec![];
+        let mut dir = tokio::fs::read_dir(cache.path()).await.unwrap();
+        while let Some(entry) = dir.next_entry().await.unwrap() {
+            left.push(entry.file_name().into_string().unwrap());
+        }
+        left.sort();
+        assert_eq!(left, [".d.png.partial", "b.png", "c.png"]);
+    }
 }
@@ -49,8 +49,9 @@ pub async fn convert_ulids(Json(data): Json<Value>) -> Result<Json<Value>, Repor
 }
 
 #[axum::debug_handler]
-#[tracing::instrument]
+#[tracing::instrument(skip(app_state))]
 pub async fn ulid_info(
+    State(app_state): State<ServerState>,
     Path(weekday): Path<u8>,
     Json(data): Json<Value>,
 ) -> Result<Json<Value>, ReportError> {
@@ -81,11 +82,11 @@ pub async fn ulid_info(
                 weekday == created_weekday as u8
             })
             .count();
+        let now: SystemTime = app_state.clock.now().into();
         let futures = ulids
             .iter()
             .filter(|ulid| {
                 let created_on: SystemTime = ulid.datetime();
-                let now = SystemTime::now();
                 created_on > now
             })
             .count();
@@ -108,3 +109,62 @@ pub async fn ulid_info(
         Err(eyre!("Not an Array").into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Duration;
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    fn state_at(start: &str) -> (ServerState, Arc<ManualClock>) {
+        let start = DateTime::parse_from_rfc3339(start)
+            .unwrap()
+            .with_timezone(&Utc);
+        let clock = Arc::new(ManualClock::new(start));
+        (ServerState::for_tests(clock.clone()), clock)
+    }
+
+    #[tokio::test]
+    async fn packets_age_with_the_clock() {
+        let (state, clock) = state_at("2023-12-12T12:00:00Z");
+        state.add_packet("packet".to_string());
+        assert_eq!(state.load_packet("packet".to_string()), Some(0));
+        clock.advance(Duration::seconds(2)).unwrap();
+        assert_eq!(state.load_packet("packet".to_string()), Some(2));
+        state.add_packet("packet".to_string());
+        clock.advance(Duration::seconds(3)).unwrap();
+        assert_eq!(state.load_packet("packet".to_string()), Some(3));
+        assert_eq!(state.load_packet("other".to_string()), None);
+    }
+
+    #[tokio::test]
+    async fn future_ulids_follow_the_clock() {
+        let (state, clock) = state_at("2023-12-24T00:00:00Z");
+        let made_at = |time: &str| {
+            let time: DateTime<Utc> = DateTime::parse_from_rfc3339(time).unwrap().into();
+            Ulid::from_datetime(time.into()).to_string()
+        };
+        let ulids = json!([
+            made_at("2023-12-23T12:00:00Z"),
+            made_at("2024-01-01T00:00:00Z")
+        ]);
+        let info = |state: ServerState| {
+            let ulids = ulids.clone();
+            async move {
+                let Ok(Json(info)) = ulid_info(State(state), Path(0), Json(ulids)).await else {
+                    panic!("ulid_info failed");
+                };
+                info
+            }
+        };
+        assert_eq!(info(state.clone()).await["in the future"], 1);
+        clock.advance(Duration::days(8)).unwrap();
+        let later = info(state).await;
+        assert_eq!(later["in the future"], 0);
+        assert_eq!(later["weekday"], 1);
+    }
+}
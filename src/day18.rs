@@ -1,12 +1,17 @@
 use axum::{
-    extract::{Path, State},
+    extract::{OriginalUri, Path, State},
     Json,
 };
 use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
-use crate::{cch_error::ReportError, day13::Order, ServerState};
+use crate::{
+    cch_error::ReportError,
+    day13::Order,
+    pagination::{link_headers, Keyset, LinkHeader, Pagination, SortColumn, SortOrder, Sorting},
+    ServerState,
+};
 
 pub async fn reset_table(State(state): State<ServerState>) -> Result<(), ReportError> {
     sqlx::query("DROP TABLE IF EXISTS regions")
@@ -108,20 +113,51 @@ pub struct TotalReigon {
     total: i64,
 }
 
+impl Keyset for TotalReigon {
+    fn column_value(&self, column: &str) -> Option<String> {
+        match column {
+            "region" => Some(self.region.clone()),
+            "total" => Some(self.total.to_string()),
+            _ => None,
+        }
+    }
+}
+
+const REGION: SortColumn = SortColumn {
+    name: "region",
+    sql_type: "TEXT",
+};
+
+const TOTAL_SORTING: Sorting = Sorting {
+    columns: &[
+        SortColumn {
+            name: "total",
+            sql_type: "BIGINT",
+        },
+        REGION,
+    ],
+    default_order: SortOrder::Desc,
+    key: REGION,
+};
+
 pub async fn total_per_region(
+    uri: OriginalUri,
+    page: Pagination,
     State(state): State<ServerState>,
-) -> Result<Json<Vec<TotalReigon>>, ReportError> {
+) -> Result<(LinkHeader, Json<Vec<TotalReigon>>), ReportError> {
     let q = r#"
 SELECT name AS "region", SUM(quantity) AS "total" FROM orders
 INNER JOIN regions ON regions.id = orders.region_id
 GROUP BY name
-ORDER BY total DESC
     "#;
-    let vals = sqlx::query_as::<_, TotalReigon>(q)
-        .fetch_all(&state.pool)
-        .await
-        .unwrap();
-    Ok(Json(vals))
+    let paged = page.query(&TOTAL_SORTING, q, 1)?;
+    let mut query = sqlx::query_as::<_, TotalReigon>(&paged.sql);
+    for bind in paged.binds {
+        query = query.bind(bind);
+    }
+    let vals = query.fetch_all(&state.pool).await?;
+    let links = page.links(&uri, &TOTAL_SORTING, &vals);
+    Ok((link_headers(links), Json(vals)))
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
@@ -137,15 +173,32 @@ struct RankQueryOutput {
     quantity_rnk: i64,
 }
 
+impl Keyset for TopGift {
+    fn column_value(&self, column: &str) -> Option<String> {
+        match column {
+            "region" => Some(self.region.clone()),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 struct RegionQuery {
-    name: String,
+    region: String,
 }
 
+const TOP_LIST_SORTING: Sorting = Sorting {
+    columns: &[REGION],
+    default_order: SortOrder::Asc,
+    key: REGION,
+};
+
 pub async fn top_list(
+    uri: OriginalUri,
+    page: Pagination,
     Path(number): Path<i32>,
     State(state): State<ServerState>,
-) -> Result<Json<Vec<TopGift>>, ReportError> {
+) -> Result<(LinkHeader, Json<Vec<TopGift>>), ReportError> {
     let q = r#"
 SELECT
     region_name,
@@ -166,25 +219,35 @@ INNER JOIN regions ON orders.region_id = regions.id
 GROUP BY name, gift_name
 ) AS subsub) as sub
 WHERE
-quantity_rnk <= $1
+quantity_rnk <= $1 AND region_name = ANY($2)
     "#;
+    let paged = page.query(
+        &TOP_LIST_SORTING,
+        r#"SELECT name COLLATE "C" AS region FROM regions"#,
+        1,
+    )?;
+    let mut region_query = sqlx::query_as::<_, RegionQuery>(&paged.sql);
+    for bind in paged.binds {
+        region_query = region_query.bind(bind);
+    }
+    let all_region_names = region_query.fetch_all(&state.pool).await?;
+    let page_names: Vec<&str> = all_region_names
+        .iter()
+        .map(|region_name| region_name.region.as_str())
+        .collect();
+
     let ranks = sqlx::query_as::<_, RankQueryOutput>(q)
         .bind(number)
+        .bind(&page_names)
         .fetch_all(&state.pool)
-        .await
-        .unwrap();
-
-    let all_region_names = sqlx::query_as::<_, RegionQuery>("SELECT name FROM regions")
-        .fetch_all(&state.pool)
-        .await
-        .unwrap();
+        .await?;
 
-    let mut output: Vec<TopGift> = all_region_names
+    let output: Vec<TopGift> = all_region_names
         .into_iter()
         .map(|region_name| {
             let mut base_top_gifts: Vec<RankQueryOutput> = ranks
                 .iter()
-                .filter(|&rank| rank.region_name == region_name.name)
+                .filter(|&rank| rank.region_name == region_name.region)
                 .cloned()
                 .collect();
 
@@ -194,11 +257,11 @@ quantity_rnk <= $1
                 .map(|rank| rank.gift_name)
                 .collect();
             TopGift {
-                region: region_name.name.clone(),
+                region: region_name.region.clone(),
                 top_gifts,
             }
         })
         .collect();
-    output.sort_by_key(|tg| tg.region.clone());
-    Ok(Json(output))
+    let links = page.links(&uri, &TOP_LIST_SORTING, &output);
+    Ok((link_headers(links), Json(output)))
 }
@@ -1,15 +1,23 @@
 use std::time::Duration;
 
-use axum::extract::{Path, State};
-use color_eyre::eyre::{eyre, OptionExt};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use color_eyre::{
+    eyre::{eyre, OptionExt, WrapErr},
+    Report,
+};
 use dms_coordinates::DMS3d;
 use isocountry::{CountryCode, CountryCodeParseErr};
-use reqwest::ClientBuilder;
 use s2::{cellid::CellID, point::Point};
 use serde::Deserialize;
 use tracing::info;
 
-use crate::{cch_error::ReportError, ServerState};
+use crate::{
+    cch_error::{ReportError, StatusError},
+    ServerState,
+};
 
 pub async fn get_cell(Path(binary): Path<String>) -> Result<String, ReportError> {
     let bin = u64::from_str_radix(binary.as_ref(), 2).map_err(|_| eyre!("Not a valid binary"))?;
@@ -17,6 +25,11 @@ pub async fn get_cell(Path(binary): Path<String>) -> Result<String, ReportError>
     let point = Point(cell_id.raw_point());
     let lat = point.latitude().deg();
     let long = point.longitude().deg();
+    Ok(dms_string(lat, long)?)
+}
+
+/// Formats decimal degrees like `83°39'54.324''N 30°41'50.870''E`
+pub fn dms_string(lat: f64, long: f64) -> Result<String, Report> {
     let dms = DMS3d::from_decimal_degrees(lat, long, None);
     let lat = dms.latitude;
     let long = dms.longitude;
@@ -84,11 +97,19 @@ pub async fn get_country(
 
     let _permit = state.one_second_request_lock.acquire().await?;
 
-    let client = ClientBuilder::new()
-        .user_agent("cch23-shuttle/1.0.0")
-        .build()?;
-    let response = client.get(&api_url).send().await?;
-    let osm_response: OsmResponse = response.json().await?;
+    let response = state.nominatim.get(&api_url).await?;
+    let osm_response: OsmResponse = response
+        .error_for_status()
+        .wrap_err(StatusError::new(
+            StatusCode::BAD_GATEWAY,
+            "Nominatim returned an error",
+        ))?
+        .json()
+        .await
+        .wrap_err(StatusError::new(
+            StatusCode::BAD_GATEWAY,
+            "Nominatim returned an unexpected body",
+        ))?;
     // we need this to make sure we follow the terms of the
     // open street map terms and service
     tokio::time::sleep(Duration::from_secs(2)).await;
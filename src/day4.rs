@@ -1,20 +1,186 @@
-use axum::{response::IntoResponse, Json};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use color_eyre::eyre::{OptionExt, Report};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
 
-use crate::cch_error::ReportError;
+use crate::cch_error::{ReportError, StatusError};
 
-#[derive(Serialize, Deserialize)]
+/// NDJSON is read as a stream so it skips `DefaultBodyLimit`, this is the
+/// same 2 MiB the JSON and CSV extractors are held to
+const MAX_NDJSON_BYTES: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HerdFormat {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    line: u64,
+    error: String,
+}
+
+/// A herd of reindeer read from a JSON array, a CSV file with a header row
+/// or newline delimited JSON, depending on the `Content-Type` of the request.
+///
+/// Rows that fail to parse in the CSV and NDJSON formats are kept in `errors`
+/// instead of rejecting the whole payload.
+#[derive(Debug)]
+pub struct Herd<T> {
+    format: HerdFormat,
+    deers: Vec<T>,
+    errors: Vec<RowError>,
+}
+
+impl<T> Herd<T> {
+    /// The plain response is kept for JSON arrays, the other formats get
+    /// the result together with the row errors.
+    fn respond(self, plain: impl IntoResponse, result: Value) -> Response {
+        match self.format {
+            HerdFormat::Json => plain.into_response(),
+            HerdFormat::Csv | HerdFormat::Ndjson => {
+                Json(json!({"result": result, "errors": self.errors})).into_response()
+            }
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Herd<T> {
+    fn from_csv(body: &[u8]) -> Self {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body);
+        let mut herd = Herd {
+            format: HerdFormat::Csv,
+            deers: vec![],
+            errors: vec![],
+        };
+        let headers = match reader.headers() {
+            Ok(headers) => headers.clone(),
+            Err(err) => {
+                herd.errors.push(RowError {
+                    line: 1,
+                    error: err.to_string(),
+                });
+                return herd;
+            }
+        };
+        for record in reader.records() {
+            // both read and deserialize errors carry the position of the record
+            match record.and_then(|record| record.deserialize::<T>(Some(&headers))) {
+                Ok(deer) => herd.deers.push(deer),
+                Err(err) => herd.errors.push(RowError {
+                    line: err.position().map_or(0, |p| p.line()),
+                    error: err.to_string(),
+                }),
+            }
+        }
+        herd
+    }
+
+    fn push_ndjson_line(&mut self, line: u64, raw: &[u8]) {
+        if raw.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        match serde_json::from_slice::<T>(raw) {
+            Ok(deer) => self.deers.push(deer),
+            Err(err) => self.errors.push(RowError {
+                line,
+                error: err.to_string(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl<S, T> FromRequest<S> for Herd<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Send,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let mime = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase());
+        match mime.as_deref() {
+            Some("text/csv") => {
+                let body = Bytes::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                Ok(Herd::from_csv(&body))
+            }
+            Some("application/x-ndjson") => {
+                let mut herd = Herd {
+                    format: HerdFormat::Ndjson,
+                    deers: vec![],
+                    errors: vec![],
+                };
+                // parse every line as soon as it arrives instead of buffering the body
+                let mut stream = req.into_body().into_data_stream();
+                let mut pending: Vec<u8> = vec![];
+                let mut line = 0;
+                let mut read = 0;
+                while let Some(chunk) = stream.next().await {
+                    let chunk = chunk.map_err(|err| ReportError::from(err).into_response())?;
+                    read += chunk.len();
+                    if read > MAX_NDJSON_BYTES {
+                        let err = StatusError::new(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            "The NDJSON body is too large",
+                        );
+                        return Err(ReportError::from(Report::from(err)).into_response());
+                    }
+                    pending.extend_from_slice(&chunk);
+                    while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                        let raw: Vec<u8> = pending.drain(..=end).collect();
+                        line += 1;
+                        herd.push_ndjson_line(line, &raw);
+                    }
+                }
+                herd.push_ndjson_line(line + 1, &pending);
+                Ok(herd)
+            }
+            _ => {
+                let Json(deers) = Json::<Vec<T>>::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                Ok(Herd {
+                    format: HerdFormat::Json,
+                    deers,
+                    errors: vec![],
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Deer {
     name: String,
     strength: u32,
 }
 
-pub async fn reindeer_cheer(Json(deers): Json<Vec<Deer>>) -> impl IntoResponse {
-    let total = deers
-        .into_iter()
+pub async fn reindeer_cheer(herd: Herd<Deer>) -> Response {
+    let total = herd
+        .deers
+        .iter()
         .fold(0, |accum, deer| accum + deer.strength);
-    total.to_string()
+    herd.respond(total.to_string(), json!(total))
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,20 +196,11 @@ pub struct DeerDetailed {
     candies_eaten_yesterday: u32,
 }
 
-#[tracing::instrument]
-pub async fn reindeer_contest(
-    Json(deers): Json<Vec<DeerDetailed>>,
-) -> Result<Json<serde_json::Value>, ReportError> {
-    let fastest = deers
-        .iter()
-        .max_by(|&x, &y| (x.speed).partial_cmp(&y.speed).unwrap())
-        .unwrap();
-    let tallest = deers.iter().max_by_key(|&x| x.height).unwrap();
-    let magician = deers.iter().max_by_key(|&x| x.snow_magic_power).unwrap();
-    let consumer = deers
-        .iter()
-        .max_by_key(|&x| x.candies_eaten_yesterday)
-        .unwrap();
+fn contest_results(deers: &[DeerDetailed]) -> Option<Value> {
+    let fastest = deers.iter().max_by(|&x, &y| x.speed.total_cmp(&y.speed))?;
+    let tallest = deers.iter().max_by_key(|&x| x.height)?;
+    let magician = deers.iter().max_by_key(|&x| x.snow_magic_power)?;
+    let consumer = deers.iter().max_by_key(|&x| x.candies_eaten_yesterday)?;
 
     let fastest_str = format!(
         "Speeding past the finish line with a strength of {} is {}",
@@ -61,12 +218,57 @@ pub async fn reindeer_contest(
         "{} ate lots of candies, but also some {}",
         consumer.name, consumer.favorite_food
     );
-    let response_json = json!({
+    Some(json!({
         "fastest": fastest_str,
         "tallest": tallest_str,
         "magician": magician_str,
         "consumer": consumer_str,
         }
-    );
-    Ok(Json(response_json))
+    ))
+}
+
+#[tracing::instrument]
+pub async fn reindeer_contest(herd: Herd<DeerDetailed>) -> Result<Response, ReportError> {
+    let results = contest_results(&herd.deers);
+    if herd.format == HerdFormat::Json {
+        let response_json = results.ok_or_eyre("No reindeer in the contest")?;
+        return Ok(Json(response_json).into_response());
+    }
+    let result = results.unwrap_or(Value::Null);
+    Ok(herd.respond((), result))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+
+    use super::*;
+
+    async fn ndjson(body: String) -> Result<Herd<Deer>, Response> {
+        let req = Request::builder()
+            .header(CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(body))
+            .unwrap();
+        Herd::from_request(req, &()).await
+    }
+
+    #[tokio::test]
+    async fn ndjson_keeps_row_errors() {
+        let herd = ndjson("{\"name\":\"a\",\"strength\":2}\nnope\n".to_string())
+            .await
+            .unwrap();
+        assert_eq!(herd.deers.len(), 1);
+        assert_eq!(herd.errors.len(), 1);
+        assert_eq!(herd.errors[0].line, 2);
+    }
+
+    #[tokio::test]
+    async fn ndjson_is_bounded() {
+        let line = "{\"name\":\"a\",\"strength\":2}\n";
+        let body = line.repeat(MAX_NDJSON_BYTES / line.len() + 1);
+        let Err(response) = ndjson(body).await else {
+            panic!("oversized body was accepted");
+        };
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
 }
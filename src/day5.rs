@@ -1,45 +1,451 @@
-use axum::{extract::Query, Json};
-use color_eyre::eyre::eyre;
-use serde::Deserialize;
-use serde_json::Value;
+use axum::{
+    body::Body,
+    extract::{OriginalUri, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use color_eyre::eyre::{Report, WrapErr};
+use futures::StreamExt;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::{json, Value};
 
-use crate::cch_error::ReportError;
+use crate::{
+    cch_error::{ReportError, StatusError},
+    pagination::{link_header, link_headers, page_url},
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Pagination {
     offset: Option<usize>,
     limit: Option<usize>,
     split: Option<usize>,
+    #[serde(default)]
+    envelope: bool,
 }
 
-#[tracing::instrument]
+/// Bodies are streamed, this only bounds how long a request is read for
+const MAX_BODY_BYTES: usize = 256 * 1024 * 1024;
+/// JSON text one page may hold, the same as axum's default body limit
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+fn too_large(message: &str) -> Report {
+    StatusError::new(StatusCode::PAYLOAD_TOO_LARGE, message).into()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ScanState {
+    BeforeArray,
+    InArray,
+    Done,
+}
+
+/// Walks a JSON array as it arrives in chunks and only keeps the elements
+/// that fall in `offset..end`, everything else is validated and counted.
+#[derive(Debug)]
+struct PageScanner {
+    offset: usize,
+    end: usize,
+    state: ScanState,
+    /// number of elements seen so far
+    count: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    in_element: bool,
+    /// a `,` was seen and no element has started since
+    after_comma: bool,
+    element: Vec<u8>,
+    page: Vec<Value>,
+    /// JSON text of the elements in `page`
+    page_bytes: usize,
+}
+
+impl PageScanner {
+    fn new(offset: usize, limit: Option<usize>) -> Self {
+        PageScanner {
+            offset,
+            end: limit.map_or(usize::MAX, |limit| offset.saturating_add(limit)),
+            state: ScanState::BeforeArray,
+            count: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            in_element: false,
+            after_comma: false,
+            element: vec![],
+            page: vec![],
+            page_bytes: 0,
+        }
+    }
+
+    fn capturing(&self) -> bool {
+        (self.offset..self.end).contains(&self.count)
+    }
+
+    /// true once an element after the page has been seen
+    fn has_next(&self) -> bool {
+        self.count > self.end
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), Report> {
+        for &b in chunk {
+            match self.state {
+                ScanState::BeforeArray if b == b'[' => self.state = ScanState::InArray,
+                ScanState::BeforeArray if b.is_ascii_whitespace() => {}
+                ScanState::BeforeArray => {
+                    return Err(StatusError::bad_request("No Array in Body!").into())
+                }
+                ScanState::InArray => self.scan(b)?,
+                ScanState::Done if b.is_ascii_whitespace() => {}
+                ScanState::Done => {
+                    return Err(StatusError::bad_request("Unexpected data after the array").into())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, b: u8) -> Result<(), Report> {
+        if self.in_string {
+            self.push(b)?;
+            if self.escaped {
+                self.escaped = false;
+            } else if b == b'\\' {
+                self.escaped = true;
+            } else if b == b'"' {
+                self.in_string = false;
+            }
+            return Ok(());
+        }
+        match b {
+            b'"' => {
+                self.start_element();
+                self.in_string = true;
+                self.push(b)?;
+            }
+            b'[' | b'{' => {
+                self.start_element();
+                self.depth += 1;
+                self.push(b)?;
+            }
+            b']' | b'}' if self.depth > 0 => {
+                self.depth -= 1;
+                self.push(b)?;
+            }
+            b']' => {
+                if self.after_comma && !self.in_element {
+                    return Err(StatusError::bad_request("Trailing comma in the array").into());
+                }
+                self.finish_element()?;
+                self.state = ScanState::Done;
+            }
+            b',' if self.depth == 0 => {
+                if !self.in_element {
+                    return Err(StatusError::bad_request(format!(
+                        "Element {} is empty",
+                        self.count
+                    ))
+                    .into());
+                }
+                self.finish_element()?;
+                self.after_comma = true;
+            }
+            b if b.is_ascii_whitespace() => self.push(b)?,
+            b => {
+                self.start_element();
+                self.push(b)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn start_element(&mut self) {
+        if !self.in_element {
+            self.in_element = true;
+            self.after_comma = false;
+            self.element.clear();
+        }
+    }
+
+    /// Skipped elements are buffered too so they can be validated, any one
+    /// of them is held to the budget of a whole page.
+    fn push(&mut self, b: u8) -> Result<(), Report> {
+        if !self.in_element {
+            return Ok(());
+        }
+        if self.capturing() {
+            if self.page_bytes + self.element.len() >= MAX_PAGE_BYTES {
+                return Err(too_large("The page is too large, ask for a smaller limit"));
+            }
+        } else if self.element.len() >= MAX_PAGE_BYTES {
+            return Err(too_large(&format!("Element {} is too large", self.count)));
+        }
+        self.element.push(b);
+        Ok(())
+    }
+
+    fn finish_element(&mut self) -> Result<(), Report> {
+        if !self.in_element {
+            return Ok(());
+        }
+        let invalid =
+            || StatusError::bad_request(format!("Element {} isn't valid JSON", self.count));
+        if self.capturing() {
+            let element = serde_json::from_slice(&self.element).wrap_err_with(invalid)?;
+            self.page.push(element);
+            self.page_bytes += self.element.len();
+        } else {
+            serde_json::from_slice::<IgnoredAny>(&self.element).wrap_err_with(invalid)?;
+        }
+        self.count += 1;
+        self.in_element = false;
+        Ok(())
+    }
+}
+
+/// Builds the `Link` header value pointing at the next and previous pages
+fn page_links(uri: &OriginalUri, offset: usize, limit: usize, has_next: bool) -> Option<String> {
+    let next =
+        (limit > 0 && has_next).then(|| page_url(uri, "offset", &(offset + limit).to_string()));
+    let prev = (offset > 0).then(|| {
+        page_url(
+            uri,
+            "offset",
+            &offset.saturating_sub(limit.max(1)).to_string(),
+        )
+    });
+    link_header(next, prev)
+}
+
+/// The body is read as a stream so memory stays bounded by the page size,
+/// reading stops after the page unless the envelope needs the total, so
+/// only the part of the body that was read is validated.
+/// Pages hold at most `MAX_PAGE_BYTES` of JSON and bodies `MAX_BODY_BYTES`.
+#[tracing::instrument(skip(body))]
 pub async fn paginate_list(
+    uri: OriginalUri,
     Query(q): Query<Pagination>,
-    Json(names): Json<serde_json::Value>,
-) -> Result<Json<serde_json::Value>, ReportError> {
-    let Value::Array(names) = names else {
-        return Err(eyre!("No Array in Body!").into());
-    };
-    if names.is_empty() {
-        return Ok(Json(Value::Array(names)));
+    body: Body,
+) -> Result<Response, ReportError> {
+    if q.split == Some(0) {
+        return Err(StatusError::bad_request("split must be greater than 0").into());
+    }
+    let mut scanner = PageScanner::new(q.offset.unwrap_or(0), q.limit);
+    let mut stream = body.into_data_stream();
+    let mut stopped_early = false;
+    let mut read = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        read += chunk.len();
+        if read > MAX_BODY_BYTES {
+            return Err(too_large("The body is too large").into());
+        }
+        scanner.feed(&chunk)?;
+        if !q.envelope && scanner.has_next() {
+            stopped_early = true;
+            break;
+        }
+    }
+    match scanner.state {
+        ScanState::BeforeArray => {
+            return Err(StatusError::bad_request("No Array in Body!").into());
+        }
+        ScanState::InArray if !stopped_early => {
+            return Err(StatusError::bad_request("Body ended inside the array").into());
+        }
+        _ => {}
     }
-    let offset = q.offset.unwrap_or(0);
-    let limit = match q.limit {
-        Some(limit) => limit,
-        None => names.len() - offset,
+    let total = scanner.count;
+    let offset = scanner.offset.min(total);
+    // the page asked for, which can be longer than what is left
+    let limit = q.limit.unwrap_or(scanner.page.len());
+    let has_next = scanner.has_next();
+    let items = match q.split {
+        Some(split) => scanner
+            .page
+            .chunks(split)
+            .map(|c| Value::Array(c.to_vec()))
+            .collect(),
+        None => scanner.page,
+    };
+    let links = page_links(&uri, offset, limit, has_next);
+    let body = if q.envelope {
+        let next_offset = (limit > 0 && has_next).then_some(offset + limit);
+        json!({
+            "items": items,
+            "total": total,
+            "offset": offset,
+            "limit": limit,
+            "next_offset": next_offset,
+        })
+    } else {
+        Value::Array(items)
     };
-    match q.split {
-        Some(split) => {
-            let paged_names = &names[offset..offset + limit];
-            let split_names: Vec<_> = paged_names
-                .chunks(split)
-                .map(|c| Value::Array(c.to_vec()))
-                .collect();
-            Ok(Json(Value::Array(split_names)))
-        }
-        None => {
-            let paged_names = &names[offset..offset + limit];
-            Ok(Json(Value::Array(paged_names.to_vec())))
+    Ok((link_headers(links), Json(body)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header::LINK, StatusCode};
+
+    use super::*;
+
+    fn scan(offset: usize, limit: Option<usize>, chunks: &[&str]) -> PageScanner {
+        let mut scanner = PageScanner::new(offset, limit);
+        for chunk in chunks {
+            scanner.feed(chunk.as_bytes()).unwrap();
         }
+        scanner
+    }
+
+    fn status(err: Report) -> StatusCode {
+        err.downcast_ref::<StatusError>().unwrap().status
+    }
+
+    fn uri(query: &str) -> OriginalUri {
+        OriginalUri(format!("/5?{query}").parse().unwrap())
+    }
+
+    async fn paginate(query: &str, body: &str) -> (Option<String>, Value) {
+        let q = Query::try_from_uri(&uri(query).0).unwrap();
+        let Ok(response) = paginate_list(uri(query), q, Body::from(body.to_string())).await else {
+            panic!("paginate_list failed");
+        };
+        let link = response
+            .headers()
+            .get(LINK)
+            .map(|link| link.to_str().unwrap().to_string());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (link, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn links_step_by_the_requested_limit() {
+        assert_eq!(
+            page_links(&uri("offset=10&limit=5"), 10, 5, true).unwrap(),
+            r#"</5?limit=5&offset=15>; rel="next", </5?limit=5&offset=5>; rel="prev""#
+        );
+        assert_eq!(page_links(&uri("limit=5"), 0, 5, false), None);
+        assert_eq!(
+            page_links(&uri("offset=3&limit=5"), 3, 5, false).unwrap(),
+            r#"</5?limit=5&offset=0>; rel="prev""#
+        );
+    }
+
+    #[tokio::test]
+    async fn short_last_page_keeps_the_limit() {
+        let items = (0..12).map(|i| i.to_string()).collect::<Vec<_>>().join(",");
+        let (link, body) = paginate("offset=10&limit=5&envelope=true", &format!("[{items}]")).await;
+        assert_eq!(
+            link.unwrap(),
+            r#"</5?limit=5&envelope=true&offset=5>; rel="prev""#
+        );
+        assert_eq!(
+            body,
+            json!({"items": [10, 11], "total": 12, "offset": 10, "limit": 5, "next_offset": null})
+        );
+
+        let (link, body) = paginate("offset=20&limit=5&envelope=true", &format!("[{items}]")).await;
+        assert_eq!(
+            link.unwrap(),
+            r#"</5?limit=5&envelope=true&offset=7>; rel="prev""#
+        );
+        assert_eq!(body["offset"], 12);
+        assert_eq!(body["limit"], 5);
+    }
+
+    #[tokio::test]
+    async fn raw_array_stays_compatible() {
+        let (link, body) = paginate("offset=1&limit=2&split=1", r#"["a","b","c","d"]"#).await;
+        assert_eq!(body, json!([["b"], ["c"]]));
+        assert!(link.unwrap().contains("offset=3"));
+    }
+
+    #[test]
+    fn pages_across_chunks() {
+        let scanner = scan(1, Some(2), &["[1, \"a,", "]\", {\"b\": [2, 3]}", ", 4]"]);
+        assert_eq!(scanner.state, ScanState::Done);
+        assert_eq!(scanner.count, 4);
+        assert_eq!(scanner.page, vec![json!("a,]"), json!({"b": [2, 3]})]);
+        assert!(scanner.has_next());
+    }
+
+    #[test]
+    fn escaped_quotes_stay_in_strings() {
+        let scanner = scan(0, None, &[r#"["a\"]", "\\"]"#]);
+        assert_eq!(scanner.page, vec![json!("a\"]"), json!("\\")]);
+    }
+
+    #[test]
+    fn empty_array() {
+        let scanner = scan(0, None, &[" [ ] "]);
+        assert_eq!(scanner.state, ScanState::Done);
+        assert_eq!(scanner.count, 0);
+        assert!(scanner.page.is_empty());
+    }
+
+    #[test]
+    fn skipped_elements_are_counted() {
+        let scanner = scan(2, Some(1), &["[[1, 2], {}, 3, 4]"]);
+        assert_eq!(scanner.count, 4);
+        assert_eq!(scanner.page, vec![json!(3)]);
+    }
+
+    #[test]
+    fn truncated_body_stays_in_array() {
+        let scanner = scan(0, None, &["[1,2"]);
+        assert_eq!(scanner.state, ScanState::InArray);
+        assert_eq!(scanner.page, vec![json!(1)]);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut scanner = PageScanner::new(0, None);
+        let err = scanner.feed(b"{}").unwrap_err();
+        assert_eq!(status(err), StatusCode::BAD_REQUEST);
+
+        let mut scanner = PageScanner::new(0, None);
+        let err = scanner.feed(b"[1, tru]").unwrap_err();
+        assert_eq!(status(err), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn pages_are_bounded_in_bytes() {
+        let element = format!("\"{}\"", "a".repeat(MAX_PAGE_BYTES / 4));
+        let body = format!("[{}]", [element.as_str(); 8].join(","));
+
+        let mut scanner = PageScanner::new(0, Some(2));
+        scanner.feed(body.as_bytes()).unwrap();
+        assert_eq!(scanner.page.len(), 2);
+
+        let mut scanner = PageScanner::new(0, None);
+        let err = scanner.feed(body.as_bytes()).unwrap_err();
+        assert_eq!(status(err), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn rejects_malformed_arrays() {
+        for body in [
+            "[1,,2]", "[,]", "[1,]", "[1 2]", "[1] x", "[1]]", "[tru, 1]",
+        ] {
+            let mut scanner = PageScanner::new(1, Some(1));
+            let err = scanner.feed(body.as_bytes()).unwrap_err();
+            assert_eq!(status(err), StatusCode::BAD_REQUEST, "{body}");
+        }
+        let scanner = scan(0, None, &["[1, 2]\n", " "]);
+        assert_eq!(scanner.state, ScanState::Done);
+    }
+
+    #[tokio::test]
+    async fn trailing_data_is_read() {
+        let q = Query::try_from_uri(&uri("limit=1").0).unwrap();
+        let body = Body::from("[1] [2]");
+        let Err(err) = paginate_list(uri("limit=1"), q, body).await else {
+            panic!("trailing data was accepted");
+        };
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
     }
 }
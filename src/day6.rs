@@ -1,15 +1,424 @@
-use axum::{response::IntoResponse, Json};
-use fancy_regex::Regex;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+};
+
+use aho_corasick::AhoCorasick;
+use axum::{
+    extract::Query,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tera::{Context, Tera};
+
+use crate::cch_error::{ReportError, StatusError};
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PhraseOptions {
+    phrase: String,
+    /// key used in the response, defaults to the phrase
+    name: Option<String>,
+    #[serde(default)]
+    overlapping: bool,
+    #[serde(default = "default_true")]
+    case_sensitive: bool,
+    #[serde(default)]
+    whole_word: bool,
+}
+
+impl PhraseOptions {
+    fn new(phrase: &str) -> Self {
+        PhraseOptions {
+            phrase: phrase.to_string(),
+            name: None,
+            overlapping: false,
+            case_sensitive: true,
+            whole_word: false,
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.phrase)
+    }
+}
+
+/// Count of `count` minus the count of `without`, e.g. shelves without an elf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DerivedCount {
+    name: String,
+    count: String,
+    without: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CountRequest {
+    text: String,
+    phrases: Vec<PhraseOptions>,
+    #[serde(default)]
+    derived: Vec<DerivedCount>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountFormat {
+    #[default]
+    Json,
+    Html,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CountOptions {
+    /// include the location of every match in the response
+    #[serde(default)]
+    positions: bool,
+    #[serde(default)]
+    format: CountFormat,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CountResponse {
+    counts: BTreeMap<String, usize>,
+    derived: BTreeMap<String, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<MatchPosition>>,
+}
+
+/// Where a counted match is, lines and columns start at 1 and columns are in chars
+#[derive(Debug, Serialize)]
+pub struct MatchPosition {
+    name: String,
+    byte_start: usize,
+    byte_end: usize,
+    char_start: usize,
+    char_end: usize,
+    line: usize,
+    column: usize,
+}
+
+/// A counted match of the phrase at `index`
+#[derive(Debug, Clone)]
+struct FoundMatch {
+    index: usize,
+    range: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    char_offset: usize,
+    line: usize,
+    column: usize,
+}
+
+/// Finds the char offset, line and column of each byte offset in one sweep over `text`
+fn locate(text: &str, byte_offsets: &BTreeSet<usize>) -> BTreeMap<usize, Location> {
+    let mut locations = BTreeMap::new();
+    let mut wanted = byte_offsets.iter().peekable();
+    let mut location = Location {
+        char_offset: 0,
+        line: 1,
+        column: 1,
+    };
+    for (byte_offset, c) in text.char_indices().chain([(text.len(), '\0')]) {
+        while let Some(&&offset) = wanted.peek() {
+            if offset > byte_offset {
+                break;
+            }
+            locations.insert(offset, location);
+            wanted.next();
+        }
+        location.char_offset += 1;
+        if c == '\n' {
+            location.line += 1;
+            location.column = 1;
+        } else {
+            location.column += 1;
+        }
+    }
+    locations
+}
+
+fn match_positions(
+    text: &str,
+    phrases: &[PhraseOptions],
+    found: &[FoundMatch],
+) -> Vec<MatchPosition> {
+    let offsets: BTreeSet<usize> = found
+        .iter()
+        .flat_map(|found| [found.range.start, found.range.end])
+        .collect();
+    let locations = locate(text, &offsets);
+    let mut positions: Vec<MatchPosition> = found
+        .iter()
+        .map(|found| {
+            let start = locations[&found.range.start];
+            MatchPosition {
+                name: phrases[found.index].name().to_string(),
+                byte_start: found.range.start,
+                byte_end: found.range.end,
+                char_start: start.char_offset,
+                char_end: locations[&found.range.end].char_offset,
+                line: start.line,
+                column: start.column,
+            }
+        })
+        .collect();
+    positions.sort_by_key(|p| (p.byte_start, p.byte_end));
+    positions
+}
+
+#[derive(Debug, Serialize)]
+struct Segment<'a> {
+    text: &'a str,
+    mark: bool,
+    names: String,
+}
+
+/// Splits `text` into marked and unmarked segments, overlapping matches are merged
+fn highlight_segments<'a>(
+    text: &'a str,
+    phrases: &[PhraseOptions],
+    found: &[FoundMatch],
+) -> Vec<Segment<'a>> {
+    let mut sorted: Vec<&FoundMatch> = found.iter().collect();
+    sorted.sort_by_key(|found| found.range.start);
+    let mut spans: Vec<(Range<usize>, BTreeSet<&str>)> = vec![];
+    for FoundMatch { index, range } in sorted {
+        let name = phrases[*index].name();
+        match spans.last_mut() {
+            Some((span, names)) if range.start < span.end => {
+                span.end = span.end.max(range.end);
+                names.insert(name);
+            }
+            _ => spans.push((range.clone(), BTreeSet::from([name]))),
+        }
+    }
+    let mut segments = vec![];
+    let mut position = 0;
+    for (span, names) in spans {
+        if position < span.start {
+            segments.push(Segment {
+                text: &text[position..span.start],
+                mark: false,
+                names: String::new(),
+            });
+        }
+        segments.push(Segment {
+            text: &text[span.clone()],
+            mark: true,
+            names: names.into_iter().collect::<Vec<_>>().join(", "),
+        });
+        position = span.end;
+    }
+    if position < text.len() {
+        segments.push(Segment {
+            text: &text[position..],
+            mark: false,
+            names: String::new(),
+        });
+    }
+    segments
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Counts every phrase in a single pass over `text` with one automaton.
+///
+/// The automaton is ASCII case insensitive and reports overlapping matches,
+/// the per phrase options are applied when the matches are tallied.
+/// The counted matches are returned as well when `record` is set.
+/// Phrases can't be empty and every name has to be unique.
+fn tally_phrases(
+    text: &str,
+    phrases: &[PhraseOptions],
+    derived: &[DerivedCount],
+    record: bool,
+) -> Result<(CountResponse, Vec<FoundMatch>), ReportError> {
+    let mut names = BTreeSet::new();
+    for options in phrases {
+        if options.phrase.is_empty() {
+            return Err(StatusError::bad_request("Phrases can't be empty").into());
+        }
+        if !names.insert(options.name()) {
+            return Err(StatusError::bad_request(format!(
+                "{} names more than one phrase",
+                options.name()
+            ))
+            .into());
+        }
+    }
+    let automaton = AhoCorasick::builder()
+        .ascii_case_insensitive(true)
+        .build(phrases.iter().map(|p| &p.phrase))?;
+    let mut totals = vec![0; phrases.len()];
+    // end of the last counted match for the non overlapping phrases
+    let mut last_end = vec![0; phrases.len()];
+    let mut recorded = vec![];
+    for found in automaton.find_overlapping_iter(text) {
+        let index = found.pattern().as_usize();
+        let options = &phrases[index];
+        if options.case_sensitive && text[found.range()] != options.phrase {
+            continue;
+        }
+        if options.whole_word {
+            let before = text[..found.start()].chars().next_back();
+            let after = text[found.end()..].chars().next();
+            if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+                continue;
+            }
+        }
+        if !options.overlapping {
+            if found.start() < last_end[index] {
+                continue;
+            }
+            last_end[index] = found.end();
+        }
+        totals[index] += 1;
+        if record {
+            recorded.push(FoundMatch {
+                index,
+                range: found.range(),
+            });
+        }
+    }
+    let counts: BTreeMap<String, usize> = phrases
+        .iter()
+        .zip(totals)
+        .map(|(options, total)| (options.name().to_string(), total))
+        .collect();
+    let derived = derived
+        .iter()
+        .map(|d| {
+            let lookup = |name: &str| {
+                counts.get(name).copied().ok_or_else(|| {
+                    StatusError::bad_request(format!("{} refers to unknown phrase {name}", d.name))
+                })
+            };
+            Ok((
+                d.name.clone(),
+                lookup(&d.count)?.saturating_sub(lookup(&d.without)?),
+            ))
+        })
+        .collect::<Result<_, ReportError>>()?;
+    let counted = CountResponse {
+        counts,
+        derived,
+        matches: None,
+    };
+    Ok((counted, recorded))
+}
+
+/// The original day 6 counts
+fn elf_preset() -> (Vec<PhraseOptions>, Vec<DerivedCount>) {
+    let phrases = vec![
+        PhraseOptions::new("elf"),
+        PhraseOptions {
+            overlapping: true,
+            ..PhraseOptions::new("elf on a shelf")
+        },
+        PhraseOptions::new("shelf"),
+    ];
+    let derived = vec![DerivedCount {
+        name: "shelf with no elf on it".to_string(),
+        count: "shelf".to_string(),
+        without: "elf on a shelf".to_string(),
+    }];
+    (phrases, derived)
+}
 
 #[axum::debug_handler]
 #[tracing::instrument]
-pub async fn count_elves(body: String) -> impl IntoResponse {
-    let re = Regex::new(r"(?=(elf on a shelf))").unwrap();
-    let elf_count = body.matches("elf").count();
-    let elf_on_shelf = re.find_iter(&body).count();
-    let elf_no_shelf = body.matches("shelf").count() - elf_on_shelf;
-    Json(
-        json!( { "elf": elf_count, "elf on a shelf": elf_on_shelf, "shelf with no elf on it": elf_no_shelf  }),
-    )
+pub async fn count_elves(body: String) -> Result<impl IntoResponse, ReportError> {
+    let (phrases, derived) = elf_preset();
+    let (counted, _) = tally_phrases(&body, &phrases, &derived, false)?;
+    Ok(Json(
+        json!( { "elf": counted.counts["elf"], "elf on a shelf": counted.counts["elf on a shelf"], "shelf with no elf on it": counted.derived["shelf with no elf on it"]  }),
+    ))
+}
+
+#[tracing::instrument(skip(request))]
+pub async fn count_phrases(
+    Query(options): Query<CountOptions>,
+    Json(request): Json<CountRequest>,
+) -> Result<Response, ReportError> {
+    let record = options.positions || options.format == CountFormat::Html;
+    let (mut counted, found) =
+        tally_phrases(&request.text, &request.phrases, &request.derived, record)?;
+    if options.format == CountFormat::Html {
+        let tera = Tera::new("templates/*.html")?;
+        let mut context = Context::new();
+        context.insert(
+            "segments",
+            &highlight_segments(&request.text, &request.phrases, &found),
+        );
+        let response_html = tera.render("day6.html", &context)?;
+        return Ok(Html(response_html).into_response());
+    }
+    if options.positions {
+        counted.matches = Some(match_positions(&request.text, &request.phrases, &found));
+    }
+    Ok(Json(counted).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+
+    fn tally_status(phrases: &[PhraseOptions]) -> StatusCode {
+        match tally_phrases("an elf on a shelf", phrases, &[], false) {
+            Ok(_) => StatusCode::OK,
+            Err(err) => err.status(),
+        }
+    }
+
+    #[test]
+    fn elf_preset_counts() {
+        let (phrases, derived) = elf_preset();
+        let text = "elf on a shelf, a shelf, elf on a shelf on a shelf";
+        let Ok((counted, _)) = tally_phrases(text, &phrases, &derived, false) else {
+            panic!("the preset failed");
+        };
+        // every shelf holds an elf too
+        assert_eq!(counted.counts["elf"], 6);
+        assert_eq!(counted.counts["elf on a shelf"], 3);
+        assert_eq!(counted.counts["shelf"], 4);
+        assert_eq!(counted.derived["shelf with no elf on it"], 1);
+    }
+
+    #[test]
+    fn empty_and_duplicate_phrases_are_refused() {
+        assert_eq!(
+            tally_status(&[PhraseOptions::new("")]),
+            StatusCode::BAD_REQUEST
+        );
+        let twice = [PhraseOptions::new("elf"), PhraseOptions::new("elf")];
+        assert_eq!(tally_status(&twice), StatusCode::BAD_REQUEST);
+        let same_name = [
+            PhraseOptions::new("elf"),
+            PhraseOptions {
+                name: Some("elf".to_string()),
+                ..PhraseOptions::new("shelf")
+            },
+        ];
+        assert_eq!(tally_status(&same_name), StatusCode::BAD_REQUEST);
+        // the same phrase under another name is fine
+        let renamed = [
+            PhraseOptions::new("elf"),
+            PhraseOptions {
+                name: Some("any elf".to_string()),
+                case_sensitive: false,
+                ..PhraseOptions::new("elf")
+            },
+        ];
+        assert_eq!(tally_status(&renamed), StatusCode::OK);
+    }
 }
@@ -1,36 +1,145 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    sync::Arc,
+};
 
-use axum::extract::Json;
-use axum_extra::extract::CookieJar;
+use axum::{
+    body::Bytes,
+    extract::{Json, State},
+    http::StatusCode,
+};
+use axum_extra::extract::{cookie::Cookie, CookieJar};
 use base64::{engine::general_purpose, Engine};
-use color_eyre::eyre::OptionExt;
+use color_eyre::eyre::WrapErr;
 use color_eyre::Report;
-use serde::{Deserialize, Serialize};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
 use tracing::info;
+use ulid::Ulid;
 
-use crate::cch_error::ReportError;
+use crate::{
+    cch_error::{ReportError, StatusError},
+    units::{
+        in_common_unit, remaining, times_to_cover, times_within, trim, CommonAmounts, Quantity,
+    },
+    ServerState,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Secret used to sign the recipe cookies, it is never printed
+#[derive(Clone)]
+pub struct RecipeKey {
+    secret: Arc<[u8]>,
+    /// whether unsigned cookies are still accepted
+    allow_unsigned: bool,
+}
+
+impl RecipeKey {
+    pub fn new(secret: String) -> Self {
+        RecipeKey {
+            secret: secret.into_bytes().into(),
+            allow_unsigned: false,
+        }
+    }
+
+    pub fn allow_unsigned(mut self, allow: bool) -> Self {
+        self.allow_unsigned = allow;
+        self
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let signature = self.mac(payload).finalize().into_bytes();
+        format!(
+            "{payload}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+}
+
+impl Debug for RecipeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RecipeKey")
+            .field("allow_unsigned", &self.allow_unsigned)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the base64 payload of a recipe cookie.
+///
+/// Cookies in the signed `payload.signature` format are verified first.
+/// Plain base64 cookies are only accepted when no secret is set, or when the
+/// key allows unsigned cookies.
+fn verify<'a>(value: &'a str, key: Option<&RecipeKey>) -> Result<&'a str, StatusError> {
+    let Some((payload, signature)) = value.split_once('.') else {
+        return match key {
+            Some(key) if !key.allow_unsigned => {
+                Err(StatusError::bad_request("Recipe cookie isn't signed"))
+            }
+            _ => Ok(value),
+        };
+    };
+    let key =
+        key.ok_or_else(|| StatusError::bad_request("Signed recipe cookie but no secret is set"))?;
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| StatusError::bad_request("Recipe cookie signature is not base64"))?;
+    key.mac(payload)
+        .verify_slice(&signature)
+        .map_err(|_| StatusError::bad_request("Recipe cookie signature doesn't match"))?;
+    Ok(payload)
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename = "snake_case")]
 pub struct Recipe {
-    recipe: HashMap<String, u32>,
+    recipe: HashMap<String, Quantity>,
 }
 
 fn decode(bytes: &[u8]) -> Result<Vec<u8>, Report> {
-    Ok(general_purpose::STANDARD.decode(bytes)?)
+    general_purpose::STANDARD
+        .decode(bytes)
+        .wrap_err(StatusError::bad_request(
+            "Recipe cookie is not valid base64",
+        ))
 }
 
-fn into_recipe(message: Vec<u8>) -> Result<Recipe, Report> {
-    Ok(serde_json::from_slice::<Recipe>(&message)?)
+fn into_recipe<T: DeserializeOwned>(message: Vec<u8>) -> Result<T, Report> {
+    serde_json::from_slice::<T>(&message).wrap_err(StatusError::bad_request(
+        "Recipe cookie has the wrong shape",
+    ))
+}
+
+/// Looks up, verifies, decodes and parses the recipe cookie
+fn read_recipe_cookie<T: DeserializeOwned>(
+    jar: &CookieJar,
+    key: Option<&RecipeKey>,
+) -> Result<T, Report> {
+    let cookie = jar
+        .get("recipe")
+        .ok_or_else(|| StatusError::bad_request("No recipe Cookie"))?;
+    let plain_bytes = verify(cookie.value(), key)?.as_bytes();
+    let message = decode(plain_bytes)?;
+    into_recipe(message)
 }
 
 #[axum::debug_handler]
 #[tracing::instrument]
-pub async fn decode_recipe(jar: CookieJar) -> Result<Json<Recipe>, ReportError> {
-    let cookie = jar.get("recipe").ok_or_eyre("No recipe Cookie")?;
-    let plain_bytes = cookie.value().as_bytes();
-    let message = decode(plain_bytes)?;
-    let recipe = into_recipe(message)?;
+pub async fn decode_recipe(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+) -> Result<Json<Recipe>, ReportError> {
+    let recipe: Recipe = read_recipe_cookie(&jar, state.recipe_key.as_ref())?;
     Ok(Json(recipe))
 }
 
@@ -47,60 +156,961 @@ pub struct Ingredients {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BakeInput {
-    recipe: HashMap<String, u64>,
-    pantry: HashMap<String, u64>,
+    recipe: HashMap<String, Quantity>,
+    pantry: HashMap<String, Quantity>,
 }
 
 impl BakeInput {
-    fn find_amount_baked(&self) -> u64 {
+    fn find_amount_baked(&self) -> Result<u64, StatusError> {
         self.recipe
             .iter()
             .map(|(ingredient, amount)| {
-                if *amount == 0 {
-                    return Some(u64::MAX);
+                if amount.is_zero() {
+                    return Ok(u64::MAX);
                 }
-                let pantry_amount = self.pantry.get(ingredient)?;
-                pantry_amount.checked_div(*amount)
+                let Some(pantry_amount) = self.pantry.get(ingredient) else {
+                    return Ok(0);
+                };
+                Ok(match in_common_unit(ingredient, pantry_amount, amount)? {
+                    CommonAmounts::Plain(stock, amount) => stock / amount,
+                    CommonAmounts::Measured(stock, amount, _) => times_within(stock, amount),
+                })
             })
-            .map(|val| val.unwrap_or(0))
-            .min()
-            .unwrap_or(0)
+            .try_fold(u64::MAX, |fewest, baked| Ok(fewest.min(baked?)))
+            .map(|fewest| if self.recipe.is_empty() { 0 } else { fewest })
     }
-    /// assumes the pantry is adjusted
-    pub fn bake(&self) -> BakeOutput {
-        let cookies = self.find_amount_baked();
-        let new_pantry: HashMap<String, u64> = self
+
+    fn leftover(
+        ingredient: &str,
+        cookies: u64,
+        stock: &Quantity,
+        amount: &Quantity,
+    ) -> Result<Quantity, StatusError> {
+        let overflow = || StatusError::bad_request(format!("Baking overflowed the {ingredient}"));
+        match in_common_unit(ingredient, stock, amount)? {
+            CommonAmounts::Plain(stock, amount) => cookies
+                .checked_mul(amount)
+                .and_then(|used| stock.checked_sub(used))
+                .map(Quantity::Plain)
+                .ok_or_else(overflow),
+            CommonAmounts::Measured(stock_amount, amount, _) => {
+                let new_stock =
+                    remaining(stock_amount, cookies as f64 * amount).ok_or_else(overflow)?;
+                Ok(stock.with_amount(new_stock))
+            }
+        }
+    }
+
+    /// How much of `ingredient` is missing to bake `cookies` cookies, in the pantry's unit
+    fn shortfall(
+        &self,
+        ingredient: &str,
+        amount: &Quantity,
+        cookies: u64,
+    ) -> Result<Quantity, StatusError> {
+        let overflow =
+            || StatusError::bad_request(format!("Too many cookies for the {ingredient}"));
+        let empty = match amount {
+            Quantity::Plain(_) => Quantity::Plain(0),
+            Quantity::Measured { unit, .. } => Quantity::Measured {
+                amount: 0.0,
+                unit: *unit,
+            },
+        };
+        let stock = self.pantry.get(ingredient).unwrap_or(&empty);
+        match in_common_unit(ingredient, stock, amount)? {
+            CommonAmounts::Plain(stock, amount) => {
+                let needed = amount.checked_mul(cookies).ok_or_else(overflow)?;
+                Ok(Quantity::Plain(needed.saturating_sub(stock)))
+            }
+            CommonAmounts::Measured(stock, amount, unit) => {
+                let missing = amount * cookies as f64 - stock;
+                if !missing.is_finite() {
+                    return Err(overflow());
+                }
+                Ok(Quantity::Measured {
+                    amount: trim(missing),
+                    unit,
+                })
+            }
+        }
+    }
+
+    /// assumes the pantry is adjusted, the leftovers keep the pantry's units
+    pub fn bake(&self) -> Result<BakeOutput, StatusError> {
+        let cookies = self.find_amount_baked()?;
+        let new_pantry = self
             .pantry
             .iter()
             .map(|(ingredient, stock)| match self.recipe.get(ingredient) {
-                Some(recipe_amount) => {
-                    let new_stock: u64 = stock - (cookies * recipe_amount);
-                    (ingredient.clone(), new_stock)
-                }
-                None => (ingredient.clone(), *stock),
+                Some(amount) => Ok((
+                    ingredient.clone(),
+                    BakeInput::leftover(ingredient, cookies, stock, amount)?,
+                )),
+                None => Ok((ingredient.clone(), *stock)),
             })
-            .collect();
-        BakeOutput {
+            .collect::<Result<HashMap<String, Quantity>, StatusError>>()?;
+        Ok(BakeOutput {
             cookies,
             pantry: new_pantry,
-        }
+        })
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct BakeOutput {
     cookies: u64,
-    pantry: HashMap<String, u64>,
+    pantry: HashMap<String, Quantity>,
 }
 
+/// Bakes with the recipe cookie and sends the leftover pantry back in a new
+/// recipe cookie, signed when a secret is set.
 #[axum::debug_handler]
 #[tracing::instrument]
-pub async fn bake_recipe(jar: CookieJar) -> Json<BakeOutput> {
-    let cookie = jar.get("recipe").unwrap();
-    let plain_bytes = cookie.value().as_bytes();
-    let message = general_purpose::STANDARD.decode(plain_bytes).unwrap();
-    let bake_input = serde_json::from_slice::<BakeInput>(&message).unwrap();
+pub async fn bake_recipe(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Json<BakeOutput>), ReportError> {
+    let bake_input: BakeInput = read_recipe_cookie(&jar, state.recipe_key.as_ref())?;
     info!(message = ?bake_input);
-    let output = bake_input.bake();
-    Json(output)
+    let output = bake_input.bake()?;
+
+    let next_input = BakeInput {
+        recipe: bake_input.recipe,
+        pantry: output.pantry.clone(),
+    };
+    let payload = general_purpose::STANDARD.encode(serde_json::to_vec(&next_input)?);
+    let value = match &state.recipe_key {
+        Some(key) => key.sign(&payload),
+        None => payload,
+    };
+    let mut next_cookie = Cookie::new("recipe", value);
+    // the same path as a cookie set by the client, so it replaces it
+    next_cookie.set_path("/");
+    Ok((jar.add(next_cookie), Json(output)))
+}
+
+fn default_value() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WeightedRecipe {
+    name: String,
+    recipe: HashMap<String, Quantity>,
+    /// what one batch is worth, every batch counts the same by default
+    #[serde(default = "default_value")]
+    value: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OptimizeInput {
+    recipes: Vec<WeightedRecipe>,
+    pantry: HashMap<String, Quantity>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OptimizeOutput {
+    allocation: BTreeMap<String, u64>,
+    total_value: f64,
+    pantry: HashMap<String, Quantity>,
+    /// the ingredient that stops another batch of each recipe
+    binding: BTreeMap<String, Option<String>>,
+    /// false when the search budget ran out before the allocation was proven best
+    optimal: bool,
+}
+
+/// Stock of one ingredient and what a batch of each recipe needs of it, in one unit
+#[derive(Debug)]
+struct Constraint {
+    ingredient: String,
+    /// the pantry entry, leftovers are given in the same form
+    pantry: Option<Quantity>,
+    stock: f64,
+    needs: Vec<f64>,
+}
+
+/// Number of allocations tried before settling for the best one found so far
+const SEARCH_BUDGET: usize = 200_000;
+
+/// Branch and bound over the number of batches of each recipe
+#[derive(Debug)]
+struct BatchSearch<'a> {
+    values: Vec<f64>,
+    constraints: &'a [Constraint],
+    /// recipes in the order they are decided, the most efficient first
+    order: Vec<usize>,
+    remaining: Vec<f64>,
+    current: Vec<u64>,
+    best: Vec<u64>,
+    best_value: f64,
+    budget: usize,
+}
+
+impl<'a> BatchSearch<'a> {
+    fn new(values: Vec<f64>, constraints: &'a [Constraint]) -> Self {
+        let recipes = values.len();
+        // value per share of the pantry a batch uses up
+        let efficiency = |recipe: usize| {
+            let share: f64 = constraints
+                .iter()
+                .map(|c| c.needs[recipe] / c.stock.max(f64::MIN_POSITIVE))
+                .sum();
+            values[recipe] / share
+        };
+        let mut order: Vec<usize> = (0..recipes).collect();
+        order.sort_by(|&a, &b| efficiency(b).total_cmp(&efficiency(a)));
+        BatchSearch {
+            values,
+            constraints,
+            order,
+            remaining: constraints.iter().map(|c| c.stock).collect(),
+            current: vec![0; recipes],
+            best: vec![0; recipes],
+            best_value: 0.0,
+            budget: SEARCH_BUDGET,
+        }
+    }
+
+    fn max_batches(&self, recipe: usize) -> u64 {
+        self.constraints
+            .iter()
+            .zip(&self.remaining)
+            .filter(|(constraint, _)| constraint.needs[recipe] > 0.0)
+            .map(|(constraint, left)| times_within(*left, constraint.needs[recipe]))
+            .min()
+            .unwrap_or(0)
+    }
+
+    fn take(&mut self, recipe: usize, batches: f64) {
+        for (left, constraint) in self.remaining.iter_mut().zip(self.constraints) {
+            *left -= batches * constraint.needs[recipe];
+        }
+    }
+
+    /// Upper bound for the value the undecided recipes can still add. Each
+    /// recipe on its own is one bound, each ingredient filled with the recipe
+    /// that gets the most value out of it is another.
+    fn bound(&self, depth: usize) -> f64 {
+        let rest = &self.order[depth..];
+        let on_their_own = |recipes: &mut dyn Iterator<Item = &usize>| -> f64 {
+            recipes
+                .map(|&r| self.values[r] * self.max_batches(r) as f64)
+                .sum()
+        };
+        let independent = on_their_own(&mut rest.iter());
+        self.constraints
+            .iter()
+            .zip(&self.remaining)
+            .map(|(constraint, left)| {
+                let best_ratio = rest
+                    .iter()
+                    .filter(|&&r| constraint.needs[r] > 0.0)
+                    .map(|&r| self.values[r] / constraint.needs[r])
+                    .fold(0.0, f64::max);
+                let unconstrained =
+                    on_their_own(&mut rest.iter().filter(|&&r| constraint.needs[r] <= 0.0));
+                best_ratio * left.max(0.0) + unconstrained
+            })
+            .fold(independent, f64::min)
+    }
+
+    fn run(&mut self, depth: usize, value: f64) {
+        if self.budget == 0 {
+            return;
+        }
+        self.budget -= 1;
+        if depth == self.order.len() {
+            if value > self.best_value {
+                self.best_value = value;
+                self.best = self.current.clone();
+            }
+            return;
+        }
+        if value + self.bound(depth) <= self.best_value {
+            return;
+        }
+        let recipe = self.order[depth];
+        let mut batches = self.max_batches(recipe);
+        loop {
+            self.take(recipe, batches as f64);
+            self.current[recipe] = batches;
+            self.run(depth + 1, value + self.values[recipe] * batches as f64);
+            self.take(recipe, -(batches as f64));
+            if batches == 0 || self.budget == 0 {
+                break;
+            }
+            batches -= 1;
+        }
+        self.current[recipe] = 0;
+    }
+}
+
+impl OptimizeInput {
+    /// One constraint per ingredient in the pantry or any recipe, in the pantry's
+    /// unit or the first recipe unit when the pantry amount is plain
+    fn constraints(&self) -> Result<Vec<Constraint>, StatusError> {
+        let ingredients: BTreeSet<&String> = self
+            .pantry
+            .keys()
+            .chain(self.recipes.iter().flat_map(|r| r.recipe.keys()))
+            .collect();
+        ingredients
+            .into_iter()
+            .map(|ingredient| {
+                let pantry = self.pantry.get(ingredient);
+                let unit = pantry.and_then(Quantity::unit).or_else(|| {
+                    self.recipes
+                        .iter()
+                        .find_map(|r| r.recipe.get(ingredient).and_then(Quantity::unit))
+                });
+                let in_unit = |quantity: &Quantity| match unit {
+                    Some(unit) => quantity.amount_in(ingredient, unit),
+                    None => Ok(quantity.amount()),
+                };
+                let needs = self
+                    .recipes
+                    .iter()
+                    .map(|r| r.recipe.get(ingredient).map_or(Ok(0.0), in_unit))
+                    .collect::<Result<Vec<f64>, StatusError>>()?;
+                Ok(Constraint {
+                    ingredient: ingredient.clone(),
+                    pantry: pantry.copied(),
+                    stock: pantry.map_or(Ok(0.0), in_unit)?,
+                    needs,
+                })
+            })
+            .collect()
+    }
+
+    fn optimize(&self) -> Result<OptimizeOutput, StatusError> {
+        let constraints = self.constraints()?;
+        for (index, recipe) in self.recipes.iter().enumerate() {
+            if !recipe.value.is_finite() || recipe.value < 0.0 {
+                return Err(StatusError::bad_request(format!(
+                    "{} needs a finite value that isn't negative",
+                    recipe.name
+                )));
+            }
+            if constraints.iter().all(|c| c.needs[index] <= 0.0) {
+                return Err(StatusError::bad_request(format!(
+                    "{} doesn't use any ingredients",
+                    recipe.name
+                )));
+            }
+        }
+        let values = self.recipes.iter().map(|r| r.value).collect();
+        let mut search = BatchSearch::new(values, &constraints);
+        search.run(0, 0.0);
+        let optimal = search.budget > 0;
+
+        // replay the best allocation to find what is left over
+        let best = search.best.clone();
+        search.remaining = constraints.iter().map(|c| c.stock).collect();
+        for (recipe, &batches) in best.iter().enumerate() {
+            search.take(recipe, batches as f64);
+        }
+        let pantry = constraints
+            .iter()
+            .zip(&search.remaining)
+            .filter_map(|(constraint, left)| {
+                let quantity = constraint.pantry?.with_amount(trim(*left));
+                Some((constraint.ingredient.clone(), quantity))
+            })
+            .collect();
+        let binding = self
+            .recipes
+            .iter()
+            .enumerate()
+            .map(|(index, recipe)| {
+                let ingredient = constraints
+                    .iter()
+                    .zip(&search.remaining)
+                    .filter(|(constraint, _)| constraint.needs[index] > 0.0)
+                    .min_by(|(a, a_left), (b, b_left)| {
+                        (*a_left / a.needs[index]).total_cmp(&(*b_left / b.needs[index]))
+                    })
+                    .map(|(constraint, _)| constraint.ingredient.clone());
+                (recipe.name.clone(), ingredient)
+            })
+            .collect();
+        let allocation = self
+            .recipes
+            .iter()
+            .zip(&best)
+            .map(|(recipe, batches)| (recipe.name.clone(), *batches))
+            .collect();
+        Ok(OptimizeOutput {
+            allocation,
+            total_value: search.best_value,
+            pantry,
+            binding,
+            optimal,
+        })
+    }
+}
+
+/// Finds how many batches of each recipe make the most valuable use of the pantry
+#[tracing::instrument(skip(input))]
+pub async fn optimize_bake(
+    Json(input): Json<OptimizeInput>,
+) -> Result<Json<OptimizeOutput>, ReportError> {
+    Ok(Json(input.optimize()?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShoppingInput {
+    cookies: u64,
+    /// sizes the ingredients are sold in, e.g. flour in 1000 unit bags
+    #[serde(default)]
+    packages: HashMap<String, Quantity>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShoppingItem {
+    shortfall: Quantity,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    packages: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buy: Option<Quantity>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShoppingList {
+    cookies: u64,
+    items: HashMap<String, ShoppingItem>,
+}
+
+/// Rounds a shortfall up to whole packages of `package`
+fn round_to_packages(
+    ingredient: &str,
+    shortfall: &Quantity,
+    package: &Quantity,
+) -> Result<(u64, Quantity), StatusError> {
+    if package.is_zero() {
+        return Err(StatusError::bad_request(format!(
+            "The {ingredient} package can't be empty"
+        )));
+    }
+    match in_common_unit(ingredient, shortfall, package)? {
+        CommonAmounts::Plain(missing, size) => {
+            let packages = missing.div_ceil(size);
+            let buy = packages.checked_mul(size).ok_or_else(|| {
+                StatusError::bad_request(format!("Too many {ingredient} packages"))
+            })?;
+            Ok((packages, Quantity::Plain(buy)))
+        }
+        CommonAmounts::Measured(missing, size, unit) => {
+            let packages = times_to_cover(missing, size);
+            let buy = Quantity::Measured {
+                amount: trim(packages as f64 * size),
+                unit,
+            };
+            Ok((packages, buy))
+        }
+    }
+}
+
+impl BakeInput {
+    fn shopping_list(&self, input: &ShoppingInput) -> Result<ShoppingList, StatusError> {
+        let items = self
+            .recipe
+            .iter()
+            .map(|(ingredient, amount)| {
+                let shortfall = self.shortfall(ingredient, amount, input.cookies)?;
+                let rounded = input
+                    .packages
+                    .get(ingredient)
+                    .map(|package| round_to_packages(ingredient, &shortfall, package))
+                    .transpose()?;
+                let item = ShoppingItem {
+                    shortfall,
+                    packages: rounded.map(|(packages, _)| packages),
+                    buy: rounded.map(|(_, buy)| buy),
+                };
+                Ok((ingredient.clone(), item))
+            })
+            .collect::<Result<_, StatusError>>()?;
+        Ok(ShoppingList {
+            cookies: input.cookies,
+            items,
+        })
+    }
+}
+
+/// What has to be bought on top of the recipe cookie's pantry to bake the target
+#[axum::debug_handler]
+#[tracing::instrument]
+pub async fn shopping_list(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+    Json(input): Json<ShoppingInput>,
+) -> Result<Json<ShoppingList>, ReportError> {
+    let bake_input: BakeInput = read_recipe_cookie(&jar, state.recipe_key.as_ref())?;
+    Ok(Json(bake_input.shopping_list(&input)?))
+}
+
+/// Creates the tables for the server side pantry sessions if they are missing
+pub async fn create_session_tables(pool: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+CREATE TABLE IF NOT EXISTS pantry_sessions (
+  id TEXT PRIMARY KEY,
+  recipe TEXT NOT NULL
+);
+    "#,
+    )
+    .execute(pool)
+    .await?;
+    sqlx::query(
+        r#"
+CREATE TABLE IF NOT EXISTS pantry_stock (
+  session_id TEXT REFERENCES pantry_sessions(id) ON DELETE CASCADE,
+  ingredient TEXT,
+  quantity TEXT NOT NULL,
+  PRIMARY KEY (session_id, ingredient)
+);
+    "#,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+const SESSION_COOKIE: &str = "pantry_session";
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SessionInput {
+    #[serde(default)]
+    recipe: HashMap<String, Quantity>,
+    #[serde(default)]
+    pantry: HashMap<String, Quantity>,
+}
+
+fn session_id(jar: &CookieJar) -> Result<String, StatusError> {
+    jar.get(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or_else(|| StatusError::bad_request("No pantry_session Cookie"))
+}
+
+/// Locks the session so stock changes and bakes happen one at a time
+async fn lock_session(
+    tx: &mut Transaction<'_, Postgres>,
+    id: &str,
+) -> Result<BakeInput, ReportError> {
+    let recipe: Option<String> =
+        sqlx::query_scalar("SELECT recipe FROM pantry_sessions WHERE id = $1 FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?;
+    let recipe =
+        recipe.ok_or_else(|| StatusError::new(StatusCode::NOT_FOUND, "No such session"))?;
+    let stock = sqlx::query_as::<_, (String, String)>(
+        "SELECT ingredient, quantity FROM pantry_stock WHERE session_id = $1",
+    )
+    .bind(id)
+    .fetch_all(&mut **tx)
+    .await?;
+    Ok(session_from_rows(&recipe, stock)?)
+}
+
+/// A session from its stored recipe and `pantry_stock` rows, which hold
+/// every quantity as JSON text
+fn session_from_rows(
+    recipe: &str,
+    stock: Vec<(String, String)>,
+) -> Result<BakeInput, serde_json::Error> {
+    let pantry = stock
+        .into_iter()
+        .map(|(ingredient, quantity)| Ok((ingredient, serde_json::from_str(&quantity)?)))
+        .collect::<Result<_, serde_json::Error>>()?;
+    Ok(BakeInput {
+        recipe: serde_json::from_str(recipe)?,
+        pantry,
+    })
+}
+
+/// The `pantry_stock` rows `store_pantry` writes for `pantry`
+fn pantry_rows(
+    pantry: &HashMap<String, Quantity>,
+) -> Result<Vec<(String, String)>, serde_json::Error> {
+    pantry
+        .iter()
+        .map(|(ingredient, quantity)| Ok((ingredient.clone(), serde_json::to_string(quantity)?)))
+        .collect()
+}
+
+async fn store_pantry(
+    tx: &mut Transaction<'_, Postgres>,
+    id: &str,
+    pantry: &HashMap<String, Quantity>,
+) -> Result<(), ReportError> {
+    for (ingredient, quantity) in pantry_rows(pantry)? {
+        sqlx::query(
+            r#"
+INSERT INTO pantry_stock (session_id, ingredient, quantity) VALUES ($1, $2, $3)
+ON CONFLICT (session_id, ingredient) DO UPDATE SET quantity = EXCLUDED.quantity
+    "#,
+        )
+        .bind(id)
+        .bind(ingredient)
+        .bind(quantity)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// An empty body starts an empty session, anything else has to parse
+fn session_input(body: &[u8]) -> Result<SessionInput, StatusError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(SessionInput::default());
+    }
+    serde_json::from_slice(body)
+        .map_err(|err| StatusError::bad_request(format!("Invalid session: {err}")))
+}
+
+/// Starts a pantry session with an optional recipe and stock
+#[tracing::instrument(skip(state))]
+pub async fn create_session(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+    body: Bytes,
+) -> Result<(CookieJar, Json<BakeInput>), ReportError> {
+    let input = session_input(&body)?;
+    let id = Ulid::new().to_string();
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("INSERT INTO pantry_sessions (id, recipe) VALUES ($1, $2)")
+        .bind(&id)
+        .bind(serde_json::to_string(&input.recipe)?)
+        .execute(&mut *tx)
+        .await?;
+    store_pantry(&mut tx, &id, &input.pantry).await?;
+    tx.commit().await?;
+
+    let mut cookie = Cookie::new(SESSION_COOKIE, id);
+    cookie.set_path("/7");
+    let session = BakeInput {
+        recipe: input.recipe,
+        pantry: input.pantry,
+    };
+    Ok((jar.add(cookie), Json(session)))
+}
+
+/// Replaces the recipe stored in the session
+#[tracing::instrument(skip(state))]
+pub async fn set_session_recipe(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+    Json(recipe): Json<HashMap<String, Quantity>>,
+) -> Result<Json<BakeInput>, ReportError> {
+    let id = session_id(&jar)?;
+    let mut tx = state.pool.begin().await?;
+    let mut session = lock_session(&mut tx, &id).await?;
+    sqlx::query("UPDATE pantry_sessions SET recipe = $2 WHERE id = $1")
+        .bind(&id)
+        .bind(serde_json::to_string(&recipe)?)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    session.recipe = recipe;
+    Ok(Json(session))
+}
+
+/// Adds to the stock of the session's pantry, keeping the units already stored
+#[tracing::instrument(skip(state))]
+pub async fn add_session_stock(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+    Json(stock): Json<HashMap<String, Quantity>>,
+) -> Result<Json<HashMap<String, Quantity>>, ReportError> {
+    let id = session_id(&jar)?;
+    let mut tx = state.pool.begin().await?;
+    let mut session = lock_session(&mut tx, &id).await?;
+    for (ingredient, quantity) in stock {
+        let total = match session.pantry.get(&ingredient) {
+            Some(current) => current.add(&ingredient, &quantity)?,
+            None => quantity,
+        };
+        session.pantry.insert(ingredient, total);
+    }
+    store_pantry(&mut tx, &id, &session.pantry).await?;
+    tx.commit().await?;
+    Ok(Json(session.pantry))
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn list_session_stock(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+) -> Result<Json<HashMap<String, Quantity>>, ReportError> {
+    let id = session_id(&jar)?;
+    let mut tx = state.pool.begin().await?;
+    let session = lock_session(&mut tx, &id).await?;
+    tx.commit().await?;
+    Ok(Json(session.pantry))
+}
+
+/// Bakes the stored recipe and takes the used ingredients out of the stored
+/// pantry in the same transaction, so concurrent bakes can't overdraw it
+#[tracing::instrument(skip(state))]
+pub async fn bake_session(
+    State(state): State<ServerState>,
+    jar: CookieJar,
+) -> Result<Json<BakeOutput>, ReportError> {
+    let id = session_id(&jar)?;
+    let mut tx = state.pool.begin().await?;
+    let session = lock_session(&mut tx, &id).await?;
+    let output = session.bake()?;
+    store_pantry(&mut tx, &id, &output.pantry).await?;
+    tx.commit().await?;
+    Ok(Json(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        http::{
+            header::{COOKIE, SET_COOKIE},
+            HeaderMap,
+        },
+        response::IntoResponse,
+    };
+
+    use crate::{clock, units::Unit};
+
+    use super::*;
+
+    fn bake(input: serde_json::Value) -> BakeOutput {
+        serde_json::from_value::<BakeInput>(input)
+            .unwrap()
+            .bake()
+            .unwrap()
+    }
+
+    /// Bakes with `cookie` and returns the cookie the response sets
+    async fn bake_with(state: &ServerState, cookie: &str) -> (String, BakeOutput) {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("recipe={cookie}").parse().unwrap());
+        let jar = CookieJar::from_headers(&headers);
+        let Ok((jar, Json(output))) = bake_recipe(State(state.clone()), jar).await else {
+            panic!("bake_recipe failed");
+        };
+        let response = jar.into_response();
+        let set_cookie = Cookie::parse(response.headers()[SET_COOKIE].to_str().unwrap())
+            .unwrap()
+            .into_owned();
+        assert_eq!(set_cookie.name(), "recipe");
+        assert_eq!(set_cookie.path(), Some("/"));
+        (set_cookie.value().to_string(), output)
+    }
+
+    #[tokio::test]
+    async fn baking_twice_continues_from_the_leftovers() {
+        let mut state = ServerState::for_tests(clock::clock(None).unwrap());
+        state.recipe_key = Some(RecipeKey::new("secret".to_string()));
+        let input = serde_json::json!({
+            "recipe": {"flour": 100},
+            "pantry": {"flour": 250},
+        });
+        let payload = general_purpose::STANDARD.encode(input.to_string());
+        let cookie = state.recipe_key.as_ref().unwrap().sign(&payload);
+
+        let (cookie, first) = bake_with(&state, &cookie).await;
+        assert_eq!(first.cookies, 2);
+        assert_eq!(first.pantry["flour"], Quantity::Plain(50));
+        let (_, second) = bake_with(&state, &cookie).await;
+        assert_eq!(second.cookies, 0);
+        assert_eq!(second.pantry["flour"], Quantity::Plain(50));
+    }
+
+    #[tokio::test]
+    async fn unsigned_cookies_are_refused_when_signing() {
+        let mut state = ServerState::for_tests(clock::clock(None).unwrap());
+        state.recipe_key = Some(RecipeKey::new("secret".to_string()));
+        let payload = general_purpose::STANDARD.encode(r#"{"recipe":{},"pantry":{}}"#);
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("recipe={payload}").parse().unwrap());
+        let result = bake_recipe(State(state), CookieJar::from_headers(&headers)).await;
+        let Err(err) = result else {
+            panic!("an unsigned cookie was accepted");
+        };
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn session_bodies_are_empty_or_valid() {
+        assert!(session_input(b"").unwrap().pantry.is_empty());
+        assert!(session_input(b" \n").unwrap().recipe.is_empty());
+        let input = session_input(br#"{"pantry": {"flour": 3}}"#).unwrap();
+        assert_eq!(input.pantry["flour"], Quantity::Plain(3));
+        for body in [&b"{"[..], b"nope", br#"{"pantry": {"flour": -3}}"#] {
+            let err = session_input(body).unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    /// the rows a session bake stores are what the next bake locks
+    #[test]
+    fn stored_sessions_bake_from_their_leftovers() {
+        let recipe = r#"{"flour": {"amount": 300, "unit": "g"}, "eggs": 1}"#;
+        let pantry = serde_json::from_value(serde_json::json!({
+            "flour": {"amount": 1, "unit": "kg"},
+            "eggs": 5,
+        }))
+        .unwrap();
+        let mut rows = pantry_rows(&pantry).unwrap();
+        let mut baked = vec![];
+        for _ in 0..2 {
+            let output = session_from_rows(recipe, rows).unwrap().bake().unwrap();
+            rows = pantry_rows(&output.pantry).unwrap();
+            baked.push(output.cookies);
+        }
+        assert_eq!(baked, [3, 0]);
+        let session = session_from_rows(recipe, rows).unwrap();
+        assert_eq!(
+            session.pantry["flour"],
+            Quantity::Measured {
+                amount: 0.1,
+                unit: Unit::Kg,
+            }
+        );
+        assert_eq!(session.pantry["eggs"], Quantity::Plain(2));
+    }
+
+    #[test]
+    fn leftovers_keep_the_pantry_units() {
+        let output = bake(serde_json::json!({
+            "recipe": {"flour": {"amount": 100, "unit": "g"}, "milk": {"amount": 50, "unit": "ml"}},
+            "pantry": {"flour": {"amount": 1.05, "unit": "kg"}, "milk": {"amount": 0.55, "unit": "l"}},
+        }));
+        assert_eq!(output.cookies, 10);
+        assert_eq!(
+            output.pantry["flour"],
+            Quantity::Measured {
+                amount: 0.05,
+                unit: Unit::Kg
+            }
+        );
+        assert_eq!(
+            output.pantry["milk"],
+            Quantity::Measured {
+                amount: 0.05,
+                unit: Unit::L
+            }
+        );
+    }
+
+    #[test]
+    fn plain_pantry_entries_stay_plain() {
+        let output = bake(serde_json::json!({
+            "recipe": {"sugar": {"amount": 2.5, "unit": "g"}},
+            "pantry": {"sugar": 11, "butter": 3},
+        }));
+        assert_eq!(output.cookies, 4);
+        assert_eq!(output.pantry["sugar"], Quantity::Plain(1));
+        assert_eq!(output.pantry["butter"], Quantity::Plain(3));
+    }
+
+    fn constraint(stock: f64, needs: &[f64]) -> Constraint {
+        Constraint {
+            ingredient: "flour".to_string(),
+            pantry: Some(Quantity::Plain(stock as u64)),
+            stock,
+            needs: needs.to_vec(),
+        }
+    }
+
+    fn search(values: &[f64], constraints: &[Constraint]) -> (Vec<u64>, f64) {
+        let mut search = BatchSearch::new(values.to_vec(), constraints);
+        search.run(0, 0.0);
+        assert!(search.budget > 0);
+        (search.best, search.best_value)
+    }
+
+    /// Best value over every allocation of up to `limit` batches per recipe
+    fn brute_force(values: &[f64], constraints: &[Constraint], limit: u64) -> f64 {
+        let mut best = 0.0;
+        let mut batches = vec![0; values.len()];
+        loop {
+            let fits = constraints.iter().all(|c| {
+                let used: f64 = c
+                    .needs
+                    .iter()
+                    .zip(&batches)
+                    .map(|(n, &b)| n * b as f64)
+                    .sum();
+                used <= c.stock + 1e-9
+            });
+            if fits {
+                let value: f64 = values
+                    .iter()
+                    .zip(&batches)
+                    .map(|(v, &b)| v * b as f64)
+                    .sum();
+                best = f64::max(best, value);
+            }
+            let Some(next) = batches.iter().position(|&b| b < limit) else {
+                return best;
+            };
+            batches[next] += 1;
+            batches[..next].fill(0);
+        }
+    }
+
+    #[test]
+    fn search_beats_greedy() {
+        // the most efficient recipe leaves flour unused
+        let (best, value) = search(&[7.0, 5.0], &[constraint(10.0, &[6.0, 5.0])]);
+        assert_eq!(best, vec![0, 2]);
+        assert_eq!(value, 10.0);
+    }
+
+    #[test]
+    fn search_matches_brute_force() {
+        let constraints = [
+            constraint(23.0, &[3.0, 5.0, 0.0, 7.0]),
+            constraint(17.0, &[2.0, 0.0, 4.0, 1.0]),
+            constraint(11.0, &[0.0, 2.0, 3.0, 2.0]),
+        ];
+        for values in [
+            [1.0, 1.0, 1.0, 1.0],
+            [3.0, 5.0, 4.0, 9.0],
+            [2.5, 0.0, 7.0, 1.0],
+            [10.0, 1.0, 1.0, 20.0],
+        ] {
+            let (best, value) = search(&values, &constraints);
+            assert_eq!(value, brute_force(&values, &constraints, 12), "{values:?}");
+            for c in &constraints {
+                let used: f64 = c.needs.iter().zip(&best).map(|(n, &b)| n * b as f64).sum();
+                assert!(used <= c.stock);
+            }
+        }
+    }
+
+    #[test]
+    fn optimize_reports_leftovers_and_binding() {
+        let input: OptimizeInput = serde_json::from_value(serde_json::json!({
+            "recipes": [
+                {"name": "cookie", "recipe": {"flour": {"amount": 100, "unit": "g"}, "sugar": 1}, "value": 1},
+                {"name": "cake", "recipe": {"flour": {"amount": 0.5, "unit": "kg"}}, "value": 4},
+            ],
+            "pantry": {"flour": {"amount": 1.1, "unit": "kg"}, "sugar": 3},
+        }))
+        .unwrap();
+        let output = input.optimize().unwrap();
+        assert!(output.optimal);
+        assert_eq!(output.allocation["cake"], 2);
+        assert_eq!(output.allocation["cookie"], 1);
+        assert_eq!(output.total_value, 9.0);
+        assert_eq!(output.pantry["sugar"], Quantity::Plain(2));
+        assert_eq!(
+            output.pantry["flour"],
+            Quantity::Measured {
+                amount: 0.0,
+                unit: Unit::Kg
+            }
+        );
+        assert_eq!(output.binding["cake"].as_deref(), Some("flour"));
+    }
 }
@@ -1,34 +1,720 @@
-use axum::{extract::Path, response::IntoResponse};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::File,
+    io,
+    path::Path as FsPath,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+};
+use color_eyre::eyre::WrapErr;
+use futures::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use shuttle_secrets::SecretStore;
+use sqlx::PgPool;
+use tokio::sync::OnceCell;
 use tracing::info;
 
-#[derive(Serialize, Deserialize)]
-pub struct PokeResponse {
+use crate::{
+    cch_error::{ReportError, StatusError},
+    pagination::SortOrder,
+    physics::{DropParams, Impact},
+    upstream::Upstream,
+    ServerState,
+};
+
+const POKEAPI_URL: &str = "https://pokeapi.co/api/v2/pokemon/";
+
+/// A Pokémon, the weight is in hectograms like PokéAPI reports it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pokemon {
     weight: f64,
 }
 
+/// Somewhere Pokémon can be looked up by their Pokédex number
+#[async_trait]
+pub trait PokedexSource: Debug + Send + Sync {
+    async fn pokemon(&self, number: u32) -> Result<Pokemon, ReportError>;
+
+    /// hit and miss counts when the source is a cache
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+fn not_found(number: u32) -> StatusError {
+    StatusError::new(
+        StatusCode::NOT_FOUND,
+        format!("No Pokémon with Pokédex number {number}"),
+    )
+}
+
+/// Looks Pokémon up in a PokéAPI compatible service
+#[derive(Debug)]
+pub struct HttpPokedex {
+    upstream: Arc<Upstream>,
+    base_url: String,
+}
+
+impl HttpPokedex {
+    pub fn new(upstream: Arc<Upstream>, base_url: impl Into<String>) -> Self {
+        HttpPokedex {
+            upstream,
+            base_url: base_url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl PokedexSource for HttpPokedex {
+    async fn pokemon(&self, number: u32) -> Result<Pokemon, ReportError> {
+        let url = format!("{}/{number}/", self.base_url.trim_end_matches('/'));
+        let response = self.upstream.get(&url).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(not_found(number).into());
+        }
+        let response = response.error_for_status().wrap_err(StatusError::new(
+            StatusCode::BAD_GATEWAY,
+            "Pokédex returned an error",
+        ))?;
+        let pokemon = response.json().await.wrap_err(StatusError::new(
+            StatusCode::BAD_GATEWAY,
+            "Pokédex returned an unexpected body",
+        ))?;
+        Ok(pokemon)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DatasetEntry {
+    id: u32,
+    weight: f64,
+}
+
+/// Looks Pokémon up in a dataset read at startup, a JSON array or a CSV file
+/// with at least the columns `id` and `weight`
+#[derive(Debug)]
+pub struct LocalPokedex {
+    entries: HashMap<u32, Pokemon>,
+}
+
+impl LocalPokedex {
+    pub fn load(path: impl AsRef<FsPath>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let entries: Vec<DatasetEntry> = if path.extension().is_some_and(|ext| ext == "csv") {
+            csv::Reader::from_reader(file)
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+        } else {
+            serde_json::from_reader(file)?
+        };
+        let entries = entries
+            .into_iter()
+            .map(|entry| {
+                let pokemon = Pokemon {
+                    weight: entry.weight,
+                };
+                (entry.id, pokemon)
+            })
+            .collect();
+        Ok(LocalPokedex { entries })
+    }
+}
+
+#[async_trait]
+impl PokedexSource for LocalPokedex {
+    async fn pokemon(&self, number: u32) -> Result<Pokemon, ReportError> {
+        let pokemon = self.entries.get(&number).ok_or_else(|| not_found(number))?;
+        Ok(pokemon.clone())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    /// lookups answered by the Postgres cache
+    db_hits: u64,
+    misses: u64,
+    /// misses that waited on a lookup already in flight
+    coalesced: u64,
+    evictions: u64,
+    entries: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    db_hits: AtomicU64,
+    misses: AtomicU64,
+    coalesced: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    pokemon: Pokemon,
+    fetched: Instant,
+}
+
+/// The outcome of a lookup, shared by every caller that waits on it
+type Lookup = OnceCell<Result<Pokemon, StatusError>>;
+
+/// Caches another source in memory, and in Postgres when a pool is given.
+///
+/// Entries expire after `ttl` and the oldest entry makes way once `capacity`
+/// is reached. Concurrent misses for the same number share one lookup.
+#[derive(Debug)]
+pub struct CachedPokedex {
+    inner: Arc<dyn PokedexSource>,
+    ttl: Duration,
+    capacity: usize,
+    pool: Option<PgPool>,
+    entries: Mutex<HashMap<u32, CacheEntry>>,
+    in_flight: Mutex<HashMap<u32, Arc<Lookup>>>,
+    counters: Counters,
+}
+
+impl CachedPokedex {
+    pub fn new(inner: Arc<dyn PokedexSource>, ttl: Duration, capacity: usize) -> Self {
+        CachedPokedex {
+            inner,
+            ttl,
+            capacity,
+            pool: None,
+            entries: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Also keeps the cached Pokémon in Postgres so they outlive restarts
+    pub async fn with_pool(mut self, pool: PgPool) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            r#"
+CREATE TABLE IF NOT EXISTS pokemon_cache (
+  number INTEGER PRIMARY KEY,
+  pokemon TEXT NOT NULL,
+  fetched_at BIGINT NOT NULL
+);
+    "#,
+        )
+        .execute(&pool)
+        .await?;
+        self.pool = Some(pool);
+        Ok(self)
+    }
+
+    fn cached(&self, number: u32) -> Option<Pokemon> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&number)?;
+        (entry.fetched.elapsed() < self.ttl).then(|| entry.pokemon.clone())
+    }
+
+    fn store(&self, number: u32, pokemon: &Pokemon) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&number) && entries.len() >= self.capacity {
+            let before = entries.len();
+            entries.retain(|_, entry| entry.fetched.elapsed() < self.ttl);
+            if entries.len() == before {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.fetched)
+                    .map(|(number, _)| *number);
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            let evicted = (before - entries.len()) as u64;
+            self.counters
+                .evictions
+                .fetch_add(evicted, Ordering::Relaxed);
+        }
+        if self.capacity > 0 {
+            let entry = CacheEntry {
+                pokemon: pokemon.clone(),
+                fetched: Instant::now(),
+            };
+            entries.insert(number, entry);
+        }
+    }
+
+    async fn db_cached(&self, pool: &PgPool, number: u32) -> Result<Option<Pokemon>, ReportError> {
+        let oldest = unix_now() - self.ttl.as_secs() as i64;
+        let pokemon: Option<String> = sqlx::query_scalar(
+            "SELECT pokemon FROM pokemon_cache WHERE number = $1 AND fetched_at > $2",
+        )
+        .bind(number as i32)
+        .bind(oldest)
+        .fetch_optional(pool)
+        .await?;
+        Ok(pokemon.map(|p| serde_json::from_str(&p)).transpose()?)
+    }
+
+    async fn db_store(
+        &self,
+        pool: &PgPool,
+        number: u32,
+        pokemon: &Pokemon,
+    ) -> Result<(), ReportError> {
+        sqlx::query(
+            r#"
+INSERT INTO pokemon_cache (number, pokemon, fetched_at) VALUES ($1, $2, $3)
+ON CONFLICT (number) DO UPDATE SET pokemon = EXCLUDED.pokemon, fetched_at = EXCLUDED.fetched_at
+    "#,
+        )
+        .bind(number as i32)
+        .bind(serde_json::to_string(pokemon)?)
+        .bind(unix_now())
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Looks past the memory cache, first in Postgres and then upstream
+    async fn fetch(&self, number: u32) -> Result<Pokemon, ReportError> {
+        if let Some(pool) = &self.pool {
+            if let Some(pokemon) = self.db_cached(pool, number).await? {
+                self.counters.db_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(pokemon);
+            }
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        let pokemon = self.inner.pokemon(number).await?;
+        if let Some(pool) = &self.pool {
+            self.db_store(pool, number, &pokemon).await?;
+        }
+        Ok(pokemon)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            db_hits: self.counters.db_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            coalesced: self.counters.coalesced.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+/// A failed lookup in a form every caller waiting on it can be given
+fn shared_error(err: &ReportError) -> StatusError {
+    let shared = StatusError::new(err.status(), format!("{:#}", err.0));
+    match err
+        .0
+        .downcast_ref::<StatusError>()
+        .and_then(|err| err.retry_after)
+    {
+        Some(secs) => shared.with_retry_after(secs),
+        None => shared,
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[async_trait]
+impl PokedexSource for CachedPokedex {
+    async fn pokemon(&self, number: u32) -> Result<Pokemon, ReportError> {
+        if let Some(pokemon) = self.cached(number) {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(pokemon);
+        }
+        let cell = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let cell = in_flight.entry(number).or_default();
+            if cell.initialized() || Arc::strong_count(cell) > 1 {
+                self.counters.coalesced.fetch_add(1, Ordering::Relaxed);
+            }
+            cell.clone()
+        };
+        // only one caller runs the lookup, the others wait for its result,
+        // failed lookups included
+        let result = cell
+            .get_or_init(|| async { self.fetch(number).await.map_err(|err| shared_error(&err)) })
+            .await
+            .clone();
+        if let Ok(pokemon) = &result {
+            self.store(number, pokemon);
+        }
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&number)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(&number);
+        }
+        Ok(result?)
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        Some(self.stats())
+    }
+}
+
+/// The dataset in `POKEDEX_DATASET` when set, otherwise the service at
+/// `POKEDEX_URL` or PokéAPI through `pokeapi`. Lookups are cached for `POKEDEX_CACHE_TTL`
+/// seconds, up to `POKEDEX_CACHE_SIZE` of them, and also in Postgres when
+/// `POKEDEX_DB_CACHE` is `true`.
+pub async fn pokedex_source(
+    secret_store: &SecretStore,
+    pool: PgPool,
+    pokeapi: Arc<Upstream>,
+) -> Result<Arc<dyn PokedexSource>, shuttle_runtime::Error> {
+    let source: Arc<dyn PokedexSource> = match secret_store.get("POKEDEX_DATASET") {
+        Some(path) => Arc::new(LocalPokedex::load(path)?),
+        None => {
+            let base_url = secret_store
+                .get("POKEDEX_URL")
+                .unwrap_or_else(|| POKEAPI_URL.to_string());
+            Arc::new(HttpPokedex::new(pokeapi, base_url))
+        }
+    };
+    let setting = |name: &str, default: u64| {
+        secret_store
+            .get(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    let ttl = Duration::from_secs(setting("POKEDEX_CACHE_TTL", 3600));
+    let capacity = setting("POKEDEX_CACHE_SIZE", 1024) as usize;
+    let mut cache = CachedPokedex::new(source, ttl, capacity);
+    if secret_store.get("POKEDEX_DB_CACHE").as_deref() == Some("true") {
+        cache = cache
+            .with_pool(pool)
+            .await
+            .map_err(|err| shuttle_runtime::Error::Database(err.to_string()))?;
+    }
+    Ok(Arc::new(cache))
+}
+
 #[axum::debug_handler]
-#[tracing::instrument]
-pub async fn get_pokemon_weight(Path(pokenumber): Path<u32>) -> impl IntoResponse {
-    let mut base_url: String = "https://pokeapi.co/api/v2/pokemon/".into();
-    base_url.push_str(&pokenumber.to_string());
-    base_url.push('/');
-    let body: PokeResponse = reqwest::get(base_url).await.unwrap().json().await.unwrap();
+#[tracing::instrument(skip(state))]
+pub async fn get_pokemon_weight(
+    State(state): State<ServerState>,
+    Path(pokenumber): Path<u32>,
+) -> Result<String, ReportError> {
+    let body = state.pokedex.pokemon(pokenumber).await?;
     let kilo_wieght = body.weight / 10.0;
     info!(weight = %body.weight, kilo_wieght = %kilo_wieght);
-    kilo_wieght.to_string()
+    Ok(kilo_wieght.to_string())
 }
 
-#[axum::debug_handler]
-#[tracing::instrument]
-pub async fn get_pokemon_momentum(Path(pokenumber): Path<u32>) -> impl IntoResponse {
-    let mut base_url: String = "https://pokeapi.co/api/v2/pokemon/".into();
-    base_url.push_str(&pokenumber.to_string());
-    base_url.push('/');
-    let g = 9.825;
-    let body: PokeResponse = reqwest::get(base_url).await.unwrap().json().await.unwrap();
+async fn drop_pokemon(
+    state: &ServerState,
+    pokenumber: u32,
+    params: &DropParams,
+) -> Result<Impact, ReportError> {
+    let body = state.pokedex.pokemon(pokenumber).await?;
     let kilo_wieght = body.weight / 10.0;
-    let v: f64 = 2.0 * g * 10.0;
-    let p = kilo_wieght * v.sqrt();
-    p.to_string()
+    Ok(params.impact(kilo_wieght)?)
+}
+
+#[axum::debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn get_pokemon_momentum(
+    State(state): State<ServerState>,
+    Path(pokenumber): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<String, ReportError> {
+    let impact = drop_pokemon(&state, pokenumber, &params).await?;
+    Ok(impact.momentum.to_string())
+}
+
+/// The whole impact of the drop rather than just the momentum
+#[axum::debug_handler]
+#[tracing::instrument(skip(state))]
+pub async fn get_pokemon_impact(
+    State(state): State<ServerState>,
+    Path(pokenumber): Path<u32>,
+    Query(params): Query<DropParams>,
+) -> Result<Json<Impact>, ReportError> {
+    let impact = drop_pokemon(&state, pokenumber, &params).await?;
+    Ok(Json(impact))
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn pokedex_cache_stats(
+    State(state): State<ServerState>,
+) -> Result<Json<CacheStats>, ReportError> {
+    let stats = state
+        .pokedex
+        .cache_stats()
+        .ok_or_else(|| StatusError::new(StatusCode::NOT_FOUND, "Pokédex lookups aren't cached"))?;
+    Ok(Json(stats))
+}
+
+const MAX_BATCH: usize = 1000;
+const MAX_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMetric {
+    Number,
+    Weight,
+    #[default]
+    Momentum,
+    Velocity,
+    KineticEnergy,
+    Time,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    numbers: Vec<u32>,
+    #[serde(default)]
+    drop: DropParams,
+    #[serde(default)]
+    sort: BatchMetric,
+    order: Option<SortOrder>,
+    /// lookups running at once, at most `MAX_CONCURRENCY`
+    concurrency: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchRow {
+    number: u32,
+    /// in kilograms
+    weight: f64,
+    #[serde(flatten)]
+    impact: Impact,
+}
+
+impl BatchRow {
+    fn metric(&self, metric: BatchMetric) -> f64 {
+        match metric {
+            BatchMetric::Number => self.number as f64,
+            BatchMetric::Weight => self.weight,
+            BatchMetric::Momentum => self.impact.momentum,
+            BatchMetric::Velocity => self.impact.velocity,
+            BatchMetric::KineticEnergy => self.impact.kinetic_energy,
+            BatchMetric::Time => self.impact.time,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchError {
+    number: u32,
+    status: u16,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    rows: Vec<BatchRow>,
+    errors: Vec<BatchError>,
+}
+
+/// Looks up and drops many Pokémon at once, a failed lookup is reported in
+/// `errors` instead of failing the batch
+#[tracing::instrument(skip(state, request), fields(numbers = request.numbers.len()))]
+pub async fn batch_pokemon(
+    State(state): State<ServerState>,
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<BatchResponse>, ReportError> {
+    if request.numbers.len() > MAX_BATCH {
+        return Err(StatusError::bad_request(format!(
+            "At most {MAX_BATCH} Pokémon can be looked up at once"
+        ))
+        .into());
+    }
+    let concurrency = request.concurrency.unwrap_or(8).clamp(1, MAX_CONCURRENCY);
+    let state = &state;
+    let weights: Vec<(u32, Result<f64, ReportError>)> = stream::iter(request.numbers.clone())
+        .map(|number| async move {
+            let weight = state.pokedex.pokemon(number).await;
+            (number, weight.map(|pokemon| pokemon.weight / 10.0))
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    // simulating many drops with drag takes a while, so it's kept off the runtime
+    let drop = request.drop;
+    let results: Vec<(u32, Result<BatchRow, ReportError>)> =
+        tokio::task::spawn_blocking(move || {
+            weights
+                .into_iter()
+                .map(|(number, weight)| {
+                    let row = weight.and_then(|weight| {
+                        Ok(BatchRow {
+                            number,
+                            weight,
+                            impact: drop.impact(weight)?,
+                        })
+                    });
+                    (number, row)
+                })
+                .collect()
+        })
+        .await?;
+
+    let mut rows = vec![];
+    let mut errors = vec![];
+    for (number, result) in results {
+        match result {
+            Ok(row) => rows.push(row),
+            Err(err) => errors.push(BatchError {
+                number,
+                status: err.status().as_u16(),
+                error: err.0.to_string(),
+            }),
+        }
+    }
+    let order = request.order.unwrap_or(SortOrder::Desc);
+    rows.sort_by(|a, b| {
+        let ordering = a
+            .metric(request.sort)
+            .total_cmp(&b.metric(request.sort))
+            .then(a.number.cmp(&b.number));
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    });
+    errors.sort_by_key(|error| error.number);
+    Ok(Json(BatchResponse { rows, errors }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    async fn weight(source: &dyn PokedexSource, number: u32) -> Option<f64> {
+        source
+            .pokemon(number)
+            .await
+            .ok()
+            .map(|pokemon| pokemon.weight)
+    }
+
+    #[tokio::test]
+    async fn local_pokedex_reads_the_csv() {
+        let pokedex = LocalPokedex::load("assets/pokedex.csv").unwrap();
+        assert_eq!(weight(&pokedex, 25).await, Some(60.0));
+        let Err(err) = pokedex.pokemon(9999).await else {
+            panic!("an unknown number was found");
+        };
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn local_pokedex_reads_json() {
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        write!(file, r#"[{{"id": 7, "weight": 90.5, "name": "squirtle"}}]"#).unwrap();
+        let pokedex = LocalPokedex::load(file.path()).unwrap();
+        assert_eq!(weight(&pokedex, 7).await, Some(90.5));
+        assert_eq!(weight(&pokedex, 25).await, None);
+
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        write!(file, r#"[{{"id": 7}}]"#).unwrap();
+        let err = LocalPokedex::load(file.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Counts lookups and takes a while, so concurrent callers overlap
+    #[derive(Debug, Default)]
+    struct CountingPokedex {
+        calls: AtomicU64,
+        down: bool,
+    }
+
+    #[async_trait]
+    impl PokedexSource for CountingPokedex {
+        async fn pokemon(&self, number: u32) -> Result<Pokemon, ReportError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            if self.down {
+                let err = StatusError::new(StatusCode::SERVICE_UNAVAILABLE, "down");
+                return Err(err.with_retry_after(3).into());
+            }
+            Ok(Pokemon {
+                weight: f64::from(number),
+            })
+        }
+    }
+
+    fn cached(down: bool, ttl: Duration, capacity: usize) -> (Arc<CountingPokedex>, CachedPokedex) {
+        let inner = Arc::new(CountingPokedex {
+            down,
+            ..Default::default()
+        });
+        (inner.clone(), CachedPokedex::new(inner, ttl, capacity))
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_lookup() {
+        let (inner, cache) = cached(false, Duration::from_secs(60), 10);
+        let weights = futures::future::join_all((0..5).map(|_| weight(&cache, 4))).await;
+        assert_eq!(weights, [Some(4.0); 5]);
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 1);
+        let stats = cache.stats();
+        assert_eq!((stats.misses, stats.coalesced, stats.hits), (1, 4, 0));
+
+        assert_eq!(weight(&cache, 4).await, Some(4.0));
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn a_failed_lookup_reaches_every_caller() {
+        let (inner, cache) = cached(true, Duration::from_secs(60), 10);
+        let results = futures::future::join_all((0..3).map(|_| cache.pokemon(4))).await;
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 1);
+        for result in results {
+            let Err(err) = result else {
+                panic!("a failed lookup succeeded");
+            };
+            assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let shared = err.0.downcast_ref::<StatusError>().unwrap();
+            assert_eq!(shared.retry_after, Some(3));
+        }
+        // failures aren't cached
+        assert!(cache.pokemon(4).await.is_err());
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn entries_expire_after_the_ttl() {
+        let (inner, cache) = cached(false, Duration::from_millis(50), 10);
+        weight(&cache, 1).await;
+        weight(&cache, 1).await;
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        weight(&cache, 1).await;
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.stats().misses, 2);
+    }
+
+    #[tokio::test]
+    async fn the_oldest_entry_makes_way() {
+        let (inner, cache) = cached(false, Duration::from_secs(60), 2);
+        for number in [1, 2, 3] {
+            weight(&cache, number).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        // 1 was evicted, 3 is still cached
+        weight(&cache, 3).await;
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 3);
+        weight(&cache, 1).await;
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 4);
+    }
 }
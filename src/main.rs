@@ -1,24 +1,37 @@
 use std::{
     collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use assets::AssetStore;
 use axum::{
+    extract::DefaultBodyLimit,
     http::StatusCode,
+    middleware,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
-use chrono::{DateTime, Utc};
+use clock::Clock;
 use day19::BirdState;
+use day7::RecipeKey;
+use day8::PokedexSource;
+use shuttle_runtime::CustomError;
+use shuttle_secrets::SecretStore;
 use sqlx::PgPool;
 use tokio::sync::Semaphore;
-use tower_http::{services::ServeFile, trace::TraceLayer};
+use tower_http::trace::TraceLayer;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
 use tracing_tree::HierarchicalLayer;
+use upstream::{Upstream, UpstreamPolicy};
 
+mod assets;
 mod cch_error;
+mod clock;
+mod color;
 mod day1;
 mod day11;
 mod day12;
@@ -35,6 +48,13 @@ mod day5;
 mod day6;
 mod day7;
 mod day8;
+mod pagination;
+mod physics;
+mod transform;
+mod units;
+mod upstream;
+
+const MAX_ASSET_SIZE: usize = 16 * 1024 * 1024;
 
 async fn hello_world() -> &'static str {
     "Hello, world!"
@@ -51,6 +71,15 @@ struct ServerState {
     bird_state: Arc<BirdState>,
 
     one_second_request_lock: Arc<Semaphore>,
+    /// signs and verifies the day 7 recipe cookies when set
+    recipe_key: Option<RecipeKey>,
+    pokedex: Arc<dyn PokedexSource>,
+    pokeapi: Arc<Upstream>,
+    nominatim: Arc<Upstream>,
+    /// where transformed day 11 images are kept
+    transform_cache: PathBuf,
+    assets: AssetStore,
+    clock: Arc<dyn Clock>,
 }
 
 impl ServerState {
@@ -58,18 +87,46 @@ impl ServerState {
         self.packet_map
             .lock()
             .unwrap()
-            .insert(packet, DateTime::timestamp(&Utc::now()));
+            .insert(packet, self.clock.now().timestamp());
     }
 
     pub fn load_packet(&self, packet: String) -> Option<i64> {
         let map = self.packet_map.lock().unwrap();
         let start = map.get(&packet)?;
-        Some(DateTime::timestamp(&Utc::now()) - start)
+        Some(self.clock.now().timestamp() - start)
+    }
+}
+
+#[cfg(test)]
+impl ServerState {
+    /// A state for handler tests, the database is never connected to
+    fn for_tests(clock: Arc<dyn Clock>) -> Self {
+        let upstream = |name| {
+            Arc::new(Upstream::new(name, UpstreamPolicy::new(Duration::from_secs(1))).unwrap())
+        };
+        ServerState {
+            pool: sqlx::postgres::PgPoolOptions::new()
+                .connect_lazy("postgres://localhost/tests")
+                .unwrap(),
+            packet_map: Default::default(),
+            bird_state: Default::default(),
+            one_second_request_lock: Arc::new(Semaphore::new(1)),
+            recipe_key: None,
+            pokedex: Arc::new(day8::LocalPokedex::load("assets/pokedex.csv").unwrap()),
+            pokeapi: upstream("pokeapi"),
+            nominatim: upstream("nominatim"),
+            transform_cache: std::env::temp_dir().join("cch23-transforms"),
+            assets: AssetStore::new("assets", None),
+            clock,
+        }
     }
 }
 
 #[shuttle_runtime::main]
-async fn main(#[shuttle_shared_db::Postgres()] pool: PgPool) -> shuttle_axum::ShuttleAxum {
+async fn main(
+    #[shuttle_shared_db::Postgres()] pool: PgPool,
+    #[shuttle_secrets::Secrets] secret_store: SecretStore,
+) -> shuttle_axum::ShuttleAxum {
     color_eyre::install().unwrap();
 
     Registry::default()
@@ -82,26 +139,84 @@ async fn main(#[shuttle_shared_db::Postgres()] pool: PgPool) -> shuttle_axum::Sh
         .with(ErrorLayer::default())
         .init();
 
+    day7::create_session_tables(&pool)
+        .await
+        .map_err(|err| shuttle_runtime::Error::Database(err.to_string()))?;
+
+    let pokeapi =
+        UpstreamPolicy::new(Duration::from_secs(5)).with_secrets(&secret_store, "POKEAPI");
+    let pokeapi = Arc::new(Upstream::new("pokeapi", pokeapi).map_err(CustomError::new)?);
+    // nominatim's terms allow a request a second
+    let nominatim = UpstreamPolicy::new(Duration::from_secs(10))
+        .rate_limited(Duration::from_secs(1))
+        .with_secrets(&secret_store, "NOMINATIM");
+    let nominatim = Arc::new(Upstream::new("nominatim", nominatim).map_err(CustomError::new)?);
+    let pokedex = day8::pokedex_source(&secret_store, pool.clone(), pokeapi.clone()).await?;
+
     let state = ServerState {
         pool,
         packet_map: Arc::new(Mutex::new(HashMap::new())),
         bird_state: Default::default(),
 
         one_second_request_lock: Arc::new(Semaphore::new(1)),
+        recipe_key: secret_store.get("RECIPE_SECRET").map(|secret| {
+            RecipeKey::new(secret).allow_unsigned(
+                secret_store.get("RECIPE_ALLOW_UNSIGNED").as_deref() == Some("true"),
+            )
+        }),
+        pokedex,
+        pokeapi,
+        nominatim,
+        transform_cache: secret_store.get("TRANSFORM_CACHE_DIR").map_or_else(
+            || std::env::temp_dir().join("cch23-transforms"),
+            PathBuf::from,
+        ),
+        assets: AssetStore::new(
+            secret_store
+                .get("ASSET_DIR")
+                .unwrap_or_else(|| "assets".to_string()),
+            secret_store.get("ASSET_TOKEN"),
+        ),
+        clock: clock::clock(secret_store.get("MANUAL_CLOCK"))
+            .map_err(|err| CustomError::new(err).context("MANUAL_CLOCK isn't an RFC 3339 time"))?,
     };
 
+    let assets = Router::new()
+        .fallback_service(state.assets.service())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            assets::asset_headers,
+        ));
+
     let router = Router::new()
         .route("/-1/error", get(get_error))
+        .route("/admin/upstreams", get(upstream::upstream_status))
         .route("/1/*nums", get(day1::recalibrate_ids))
         .route("/4/strength", post(day4::reindeer_cheer))
         .route("/5", post(day5::paginate_list))
         .route("/4/contest", post(day4::reindeer_contest))
         .route("/6", post(day6::count_elves))
+        .route("/6/count", post(day6::count_phrases))
         .route("/7/decode", get(day7::decode_recipe))
         .route("/7/bake", get(day7::bake_recipe))
+        .route("/7/optimize", post(day7::optimize_bake))
+        .route("/7/shopping", post(day7::shopping_list))
+        .route("/7/session", post(day7::create_session))
+        .route("/7/session/recipe", put(day7::set_session_recipe))
+        .route(
+            "/7/session/stock",
+            get(day7::list_session_stock).post(day7::add_session_stock),
+        )
+        .route("/7/session/bake", post(day7::bake_session))
         .route("/8/weight/:pokenumber", get(day8::get_pokemon_weight))
         .route("/8/drop/:pokenumber", get(day8::get_pokemon_momentum))
+        .route("/8/drop/:pokenumber/impact", get(day8::get_pokemon_impact))
+        .route("/8/cache/stats", get(day8::pokedex_cache_stats))
+        .route("/8/batch", post(day8::batch_pokemon))
         .route("/11/red_pixels", post(day11::num_red_pixels))
+        .route("/11/transform", post(day11::transform_upload))
+        .route("/11/metadata", post(day11::image_metadata_list))
+        .route("/11/transform/:asset", get(day11::transform_asset))
         .route("/12/save/:packet", post(day12::save_packet))
         .route("/12/load/:packet", get(day12::load_packet))
         .route("/12/ulids", post(day12::convert_ulids))
@@ -133,9 +248,15 @@ async fn main(#[shuttle_shared_db::Postgres()] pool: PgPool) -> shuttle_axum::Sh
         .route("/21/coords/:binary", get(day21::get_cell))
         .route("/21/country/:binary", get(day21::get_country))
         .route("/22/integers", post(day22::find_no_pair))
-        .nest_service(
-            "/11/assets/decoration.png",
-            ServeFile::new("assets/decoration.png"),
+        .nest("/11/assets", assets)
+        .route("/admin/clock", get(clock::current_time))
+        .route("/admin/clock/advance/:seconds", post(clock::advance_clock))
+        .route("/admin/assets", get(assets::list_assets))
+        .route(
+            "/admin/assets/:name",
+            put(assets::upload_asset)
+                .delete(assets::delete_asset)
+                .layer(DefaultBodyLimit::max(MAX_ASSET_SIZE)),
         )
         .route("/", get(hello_world))
         .layer(TraceLayer::new_for_http())
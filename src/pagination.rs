@@ -0,0 +1,385 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, OriginalUri, Query},
+    http::{header::LINK, request::Parts, HeaderName},
+    response::AppendHeaders,
+};
+use base64::{engine::general_purpose, Engine};
+use serde::Deserialize;
+
+use crate::cch_error::{ReportError, StatusError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+
+    /// comparison that selects the rows after the cursor
+    fn keyset_op(self) -> &'static str {
+        match self {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SortColumn {
+    pub name: &'static str,
+    pub sql_type: &'static str,
+}
+
+/// How a list endpoint can be sorted
+#[derive(Debug)]
+pub struct Sorting {
+    /// columns the client may sort by, the first one is the default
+    pub columns: &'static [SortColumn],
+    pub default_order: SortOrder,
+    /// unique column used to break ties and to resume from a cursor
+    pub key: SortColumn,
+}
+
+/// Rows that can be resumed from with a keyset cursor
+pub trait Keyset {
+    /// the value of `column` in this row as text
+    fn column_value(&self, column: &str) -> Option<String>;
+}
+
+/// Offset or keyset pagination plus sorting taken from the query string
+///
+/// `cursor` resumes after the row it was created from and can't be combined
+/// with `offset`.
+#[derive(Debug, Default, Deserialize)]
+pub struct Pagination {
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub order: Option<SortOrder>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Pagination
+where
+    S: Send + Sync,
+{
+    type Rejection = ReportError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(pagination) = Query::<Pagination>::from_request_parts(parts, state)
+            .await
+            .map_err(|err| StatusError::bad_request(err.body_text()))?;
+        if pagination.offset.is_some() && pagination.cursor.is_some() {
+            return Err(
+                StatusError::bad_request("offset and cursor can't be used together").into(),
+            );
+        }
+        Ok(pagination)
+    }
+}
+
+/// A paginated SQL query and the text values to bind after the base query's own binds
+#[derive(Debug)]
+pub struct PagedQuery {
+    pub sql: String,
+    pub binds: Vec<String>,
+}
+
+fn encode_cursor(values: &[String]) -> String {
+    let json = serde_json::to_vec(values).unwrap_or_default();
+    general_purpose::URL_SAFE_NO_PAD.encode(json)
+}
+
+fn decode_cursor(cursor: &str) -> Result<Vec<String>, StatusError> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| StatusError::bad_request("invalid cursor"))
+}
+
+impl Pagination {
+    fn sort_column(&self, sorting: &Sorting) -> Result<SortColumn, StatusError> {
+        match &self.sort {
+            Some(name) => sorting
+                .columns
+                .iter()
+                .find(|column| column.name == name)
+                .copied()
+                .ok_or_else(|| StatusError::bad_request(format!("can't sort by {name}"))),
+            None => Ok(sorting.columns[0]),
+        }
+    }
+
+    /// Wraps `base` so the sorting, cursor and page size are applied by the database.
+    /// `next_bind` is the first placeholder number not used by `base`.
+    pub fn query(
+        &self,
+        sorting: &Sorting,
+        base: &str,
+        next_bind: usize,
+    ) -> Result<PagedQuery, ReportError> {
+        let sort = self.sort_column(sorting)?;
+        let order = self.order.unwrap_or(sorting.default_order);
+        let key = sorting.key;
+        let mut sql = format!("SELECT * FROM ({base}) AS page");
+        let mut binds = vec![];
+        if let Some(cursor) = &self.cursor {
+            binds = decode_cursor(cursor)?;
+            let op = order.keyset_op();
+            if sort.name == key.name && binds.len() == 1 {
+                sql.push_str(&format!(
+                    " WHERE page.{} {op} ${next_bind}::{}",
+                    key.name, key.sql_type
+                ));
+            } else if sort.name != key.name && binds.len() == 2 {
+                sql.push_str(&format!(
+                    " WHERE (page.{}, page.{}) {op} (${next_bind}::{}, ${}::{})",
+                    sort.name,
+                    key.name,
+                    sort.sql_type,
+                    next_bind + 1,
+                    key.sql_type
+                ));
+            } else {
+                return Err(StatusError::bad_request("cursor doesn't match the sort").into());
+            }
+        }
+        sql.push_str(&format!(" ORDER BY page.{} {}", sort.name, order.sql()));
+        if sort.name != key.name {
+            sql.push_str(&format!(", page.{} {}", key.name, order.sql()));
+        }
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!(" LIMIT {limit}"));
+        }
+        if let Some(offset) = self.offset {
+            sql.push_str(&format!(" OFFSET {offset}"));
+        }
+        Ok(PagedQuery { sql, binds })
+    }
+
+    /// `Link` header for a page of `rows`. Offset pages link to the next and
+    /// previous offsets, every other page links to a cursor after its last row.
+    pub fn links<T: Keyset>(
+        &self,
+        uri: &OriginalUri,
+        sorting: &Sorting,
+        rows: &[T],
+    ) -> Option<String> {
+        let limit = self.limit?;
+        let full_page = rows.len() as u64 == limit && limit > 0;
+        if let Some(offset) = self.offset {
+            let next = full_page.then(|| page_url(uri, "offset", &(offset + limit).to_string()));
+            let prev = (offset > 0)
+                .then(|| page_url(uri, "offset", &offset.saturating_sub(limit).to_string()));
+            return link_header(next, prev);
+        }
+        if !full_page {
+            return None;
+        }
+        let sort = self.sort_column(sorting).ok()?;
+        let last = rows.last()?;
+        let mut values = vec![];
+        if sort.name != sorting.key.name {
+            values.push(last.column_value(sort.name)?);
+        }
+        values.push(last.column_value(sorting.key.name)?);
+        link_header(Some(page_url(uri, "cursor", &encode_cursor(&values))), None)
+    }
+}
+
+/// The request uri with the query parameter `param` set to `value`
+pub fn page_url(uri: &OriginalUri, param: &str, value: &str) -> String {
+    let prefix = format!("{param}=");
+    let mut params: Vec<String> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty() && !p.starts_with(&prefix))
+        .map(String::from)
+        .collect();
+    params.push(format!("{prefix}{value}"));
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+/// RFC 8288 `Link` header value for the next and previous page urls
+pub fn link_header(next: Option<String>, prev: Option<String>) -> Option<String> {
+    let links: Vec<String> = [(next, "next"), (prev, "prev")]
+        .into_iter()
+        .filter_map(|(url, rel)| Some(format!("<{}>; rel=\"{rel}\"", url?)))
+        .collect();
+    if links.is_empty() {
+        None
+    } else {
+        Some(links.join(", "))
+    }
+}
+
+/// Response headers carrying an optional `Link` header
+pub type LinkHeader = AppendHeaders<Option<(HeaderName, String)>>;
+
+pub fn link_headers(link: Option<String>) -> LinkHeader {
+    AppendHeaders(link.map(|link| (LINK, link)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Request, StatusCode};
+
+    use super::*;
+
+    const SORTING: Sorting = Sorting {
+        columns: &[
+            SortColumn {
+                name: "created",
+                sql_type: "TIMESTAMPTZ",
+            },
+            SortColumn {
+                name: "id",
+                sql_type: "INTEGER",
+            },
+        ],
+        default_order: SortOrder::Asc,
+        key: SortColumn {
+            name: "id",
+            sql_type: "INTEGER",
+        },
+    };
+
+    struct Row {
+        id: i32,
+        created: &'static str,
+    }
+
+    impl Keyset for Row {
+        fn column_value(&self, column: &str) -> Option<String> {
+            match column {
+                "id" => Some(self.id.to_string()),
+                "created" => Some(self.created.to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    fn pagination(query: &str) -> Pagination {
+        let uri = format!("/orders?{query}").parse().unwrap();
+        Query::try_from_uri(&uri).unwrap().0
+    }
+
+    fn paged(query: &str) -> PagedQuery {
+        let Ok(paged) = pagination(query).query(&SORTING, "SELECT * FROM orders", 1) else {
+            panic!("{query} was refused");
+        };
+        paged
+    }
+
+    fn refused(query: &str) -> StatusCode {
+        let Err(err) = pagination(query).query(&SORTING, "SELECT * FROM orders", 1) else {
+            panic!("{query} was accepted");
+        };
+        err.status()
+    }
+
+    fn cursor(values: &[&str]) -> String {
+        encode_cursor(&values.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn keyset_predicates_follow_the_order() {
+        let after = cursor(&["2023-12-24", "7"]);
+        let asc = paged(&format!("cursor={after}&limit=10"));
+        assert_eq!(
+            asc.sql,
+            "SELECT * FROM (SELECT * FROM orders) AS page \
+             WHERE (page.created, page.id) > ($1::TIMESTAMPTZ, $2::INTEGER) \
+             ORDER BY page.created ASC, page.id ASC LIMIT 10"
+        );
+        assert_eq!(asc.binds, ["2023-12-24", "7"]);
+
+        let desc = paged(&format!("cursor={}&sort=id&order=desc", cursor(&["7"])));
+        assert_eq!(
+            desc.sql,
+            "SELECT * FROM (SELECT * FROM orders) AS page \
+             WHERE page.id < $1::INTEGER ORDER BY page.id DESC"
+        );
+        assert_eq!(desc.binds, ["7"]);
+
+        let offset = paged("offset=20&limit=10&order=desc");
+        assert_eq!(
+            offset.sql,
+            "SELECT * FROM (SELECT * FROM orders) AS page \
+             ORDER BY page.created DESC, page.id DESC LIMIT 10 OFFSET 20"
+        );
+        assert!(offset.binds.is_empty());
+    }
+
+    #[test]
+    fn cursors_resume_after_the_last_row() {
+        let rows = [
+            Row {
+                id: 3,
+                created: "2023-12-01",
+            },
+            Row {
+                id: 9,
+                created: "2023-12-02",
+            },
+        ];
+        let uri = OriginalUri("/orders?limit=2".parse().unwrap());
+        let link = pagination("limit=2").links(&uri, &SORTING, &rows).unwrap();
+        let next = link
+            .strip_prefix("</orders?")
+            .and_then(|link| link.strip_suffix(">; rel=\"next\""))
+            .unwrap();
+        assert_eq!(paged(next).binds, ["2023-12-02", "9"]);
+
+        // a short page is the last one
+        assert_eq!(pagination("limit=3").links(&uri, &SORTING, &rows), None);
+    }
+
+    #[test]
+    fn cursors_must_match_the_sort() {
+        let by_id = cursor(&["7"]);
+        assert_eq!(refused(&format!("cursor={by_id}")), StatusCode::BAD_REQUEST);
+        let by_created = cursor(&["2023-12-24", "7"]);
+        assert_eq!(
+            refused(&format!("cursor={by_created}&sort=id")),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(refused("cursor=not-a-cursor"), StatusCode::BAD_REQUEST);
+        assert_eq!(refused("sort=price"), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn offset_and_cursor_are_exclusive() {
+        let request = |query: &str| {
+            let (parts, _) = Request::builder()
+                .uri(format!("/orders?{query}"))
+                .body(())
+                .unwrap()
+                .into_parts();
+            parts
+        };
+        let mut parts = request(&format!("offset=5&cursor={}", cursor(&["7"])));
+        let Err(err) = Pagination::from_request_parts(&mut parts, &()).await else {
+            panic!("offset and cursor were both accepted");
+        };
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let mut parts = request("offset=5&limit=5");
+        let Ok(pagination) = Pagination::from_request_parts(&mut parts, &()).await else {
+            panic!("a plain offset was refused");
+        };
+        assert_eq!((pagination.offset, pagination.limit), (Some(5), Some(5)));
+    }
+}
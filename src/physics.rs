@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cch_error::StatusError;
+
+/// Gravity used by the original day 8 drop, in m/s²
+pub const DEFAULT_GRAVITY: f64 = 9.825;
+
+const DEFAULT_HEIGHT: f64 = 10.0;
+
+/// Density of air at sea level, in kg/m³
+const DEFAULT_AIR_DENSITY: f64 = 1.225;
+
+/// RK4 steps a fall is split into when the drag allows it
+const STEPS_PER_FALL: f64 = 1000.0;
+/// steps a fall may take at most, falls that need more are refused
+const MAX_STEPS: u64 = 100_000;
+
+/// Surface gravity of other bodies, in m/s²
+const GRAVITY_PRESETS: &[(&str, f64)] = &[
+    ("earth", 9.806_65),
+    ("moon", 1.62),
+    ("mercury", 3.7),
+    ("venus", 8.87),
+    ("mars", 3.721),
+    ("jupiter", 24.79),
+    ("saturn", 10.44),
+    ("uranus", 8.69),
+    ("neptune", 11.15),
+    ("pluto", 0.62),
+];
+
+/// How a drop is set up, taken from the query string or a batch request.
+/// `gravity` is a number in m/s² or a preset like `moon`, drag is only
+/// simulated when both `cross_section` and `drag_coefficient` are given.
+#[derive(Debug, Default, Deserialize)]
+pub struct DropParams {
+    /// in metres
+    height: Option<f64>,
+    gravity: Option<Gravity>,
+    /// area facing the air, in m²
+    cross_section: Option<f64>,
+    drag_coefficient: Option<f64>,
+    /// in kg/m³
+    air_density: Option<f64>,
+}
+
+/// A JSON number or text, query strings always give text so a number can
+/// still come as a preset name
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum Gravity {
+    Value(f64),
+    Preset(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Impact {
+    /// in m/s
+    pub velocity: f64,
+    /// in kg·m/s
+    pub momentum: f64,
+    /// in J
+    pub kinetic_energy: f64,
+    /// in s
+    pub time: f64,
+}
+
+fn gravity(value: &str) -> Result<f64, StatusError> {
+    if let Ok(gravity) = value.parse() {
+        return Ok(gravity);
+    }
+    GRAVITY_PRESETS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+        .map(|(_, gravity)| *gravity)
+        .ok_or_else(|| StatusError::bad_request(format!("Unknown gravity preset {value}")))
+}
+
+fn positive(name: &str, value: f64) -> Result<f64, StatusError> {
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err(StatusError::bad_request(format!(
+            "{name} must be greater than 0"
+        )))
+    }
+}
+
+impl DropParams {
+    /// Drops `mass` kilograms from rest
+    pub fn impact(&self, mass: f64) -> Result<Impact, StatusError> {
+        let height = positive("height", self.height.unwrap_or(DEFAULT_HEIGHT))?;
+        let gravity = match &self.gravity {
+            Some(Gravity::Value(value)) => positive("gravity", *value)?,
+            Some(Gravity::Preset(value)) => positive("gravity", gravity(value)?)?,
+            None => DEFAULT_GRAVITY,
+        };
+        let (velocity, time) = match (self.cross_section, self.drag_coefficient) {
+            (Some(area), Some(cd)) => {
+                let density = self.air_density.unwrap_or(DEFAULT_AIR_DENSITY);
+                let drag = 0.5
+                    * positive("air_density", density)?
+                    * positive("drag_coefficient", cd)?
+                    * positive("cross_section", area)?;
+                fall_with_drag(height, gravity, drag / positive("mass", mass)?)?
+            }
+            (None, None) => (
+                (2.0 * gravity * height).sqrt(),
+                (2.0 * height / gravity).sqrt(),
+            ),
+            _ => {
+                return Err(StatusError::bad_request(
+                    "Drag needs both cross_section and drag_coefficient",
+                ))
+            }
+        };
+        Ok(Impact {
+            velocity,
+            momentum: mass * velocity,
+            kinetic_energy: 0.5 * mass * velocity * velocity,
+            time,
+        })
+    }
+}
+
+/// Integrates `a = g - k v²` with RK4 until `height` has been fallen,
+/// `k` is the drag per unit of mass. Returns the velocity and time at impact.
+fn fall_with_drag(height: f64, gravity: f64, k: f64) -> Result<(f64, f64), StatusError> {
+    let accel = |v: f64| gravity - k * v * v;
+    let terminal = (gravity / k).sqrt();
+    // time it takes to near terminal velocity, steps much longer than this
+    // make the integration unstable
+    let settle = terminal / gravity;
+    // the fall takes at least as long as without drag or as at terminal
+    // velocity all the way, whichever is longer
+    let expected = (2.0 * height / gravity).sqrt().max(height / terminal);
+    let dt = (settle / 100.0)
+        .min(expected / STEPS_PER_FALL)
+        .max(4.0 * expected / MAX_STEPS as f64);
+    if dt > settle / 2.0 {
+        return Err(StatusError::bad_request(
+            "The drop takes too long to simulate",
+        ));
+    }
+
+    let (mut x, mut v, mut t) = (0.0, 0.0, 0.0);
+    for _ in 0..MAX_STEPS {
+        let (k1x, k1v) = (v, accel(v));
+        let (k2x, k2v) = (v + k1v * dt / 2.0, accel(v + k1v * dt / 2.0));
+        let (k3x, k3v) = (v + k2v * dt / 2.0, accel(v + k2v * dt / 2.0));
+        let (k4x, k4v) = (v + k3v * dt, accel(v + k3v * dt));
+        let next_x = x + dt / 6.0 * (k1x + 2.0 * k2x + 2.0 * k3x + k4x);
+        let next_v = v + dt / 6.0 * (k1v + 2.0 * k2v + 2.0 * k3v + k4v);
+        if next_x >= height {
+            // interpolate within the step to the moment of impact
+            let part = (height - x) / (next_x - x);
+            return Ok((v + (next_v - v) * part, t + dt * part));
+        }
+        (x, v, t) = (next_x, next_v, t + dt);
+    }
+    Err(StatusError::bad_request(
+        "The drop takes too long to simulate",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Velocity and time at impact from the closed form of `a = g - k v²`
+    fn exact(height: f64, gravity: f64, k: f64) -> (f64, f64) {
+        let terminal = (gravity / k).sqrt();
+        let velocity = terminal * (1.0 - (-2.0 * k * height).exp()).sqrt();
+        // acosh(exp(k h)) without overflowing
+        let time = terminal / gravity
+            * (k * height + (1.0 + (1.0 - (-2.0 * k * height).exp()).sqrt()).ln());
+        (velocity, time)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        let error = ((actual - expected) / expected).abs();
+        assert!(error < 1e-6, "{actual} is not close to {expected}");
+    }
+
+    #[test]
+    fn drag_matches_closed_form() {
+        for (height, gravity, k) in [(10.0, 9.825, 0.01), (10.0, 9.825, 5.0), (1000.0, 1.62, 2.0)] {
+            let (velocity, time) = fall_with_drag(height, gravity, k).unwrap();
+            let (exact_velocity, exact_time) = exact(height, gravity, k);
+            assert_close(velocity, exact_velocity);
+            assert_close(time, exact_time);
+        }
+    }
+
+    #[test]
+    fn drag_nears_terminal_velocity() {
+        let (velocity, _) = fall_with_drag(10_000.0, 9.825, 1.0).unwrap();
+        assert_close(velocity, 9.825_f64.sqrt());
+    }
+
+    #[test]
+    fn refuses_falls_that_take_too_many_steps() {
+        assert!(fall_with_drag(1e9, 9.825, 1e6).is_err());
+    }
+
+    #[test]
+    fn default_drop_has_no_drag() {
+        let impact = DropParams::default().impact(2.0).unwrap();
+        assert_close(
+            impact.velocity,
+            (2.0 * DEFAULT_GRAVITY * DEFAULT_HEIGHT).sqrt(),
+        );
+        assert_close(impact.momentum, 2.0 * impact.velocity);
+    }
+
+    #[test]
+    fn gravity_presets() {
+        assert_eq!(gravity("Moon").unwrap(), 1.62);
+        assert_eq!(gravity("3.5").unwrap(), 3.5);
+        assert!(gravity("krypton").is_err());
+    }
+
+    #[test]
+    fn gravity_is_a_number_or_a_preset() {
+        let json = |body: &str| serde_json::from_str::<DropParams>(body).unwrap().gravity;
+        assert_eq!(json(r#"{"gravity": 3.7}"#), Some(Gravity::Value(3.7)));
+        assert_eq!(
+            json(r#"{"gravity": "moon"}"#),
+            Some(Gravity::Preset("moon".to_string()))
+        );
+
+        let query = |query: &str| {
+            let uri: axum::http::Uri = format!("/8/drop/25?{query}").parse().unwrap();
+            axum::extract::Query::<DropParams>::try_from_uri(&uri)
+                .unwrap()
+                .0
+        };
+        let impact = query("gravity=1.62").impact(1.0).unwrap();
+        assert_eq!(impact, query("gravity=moon").impact(1.0).unwrap());
+        assert!(query("gravity=-1").impact(1.0).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::{io::Cursor, str::FromStr};
+
+use color_eyre::Report;
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use serde::Deserialize;
+
+use crate::cch_error::StatusError;
+
+const MAX_OPS: usize = 16;
+const MAX_SIDE: u32 = 4096;
+const MAX_SIGMA: f32 = 50.0;
+
+/// One step of a transform chain
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    /// fit within width and height, keeping the aspect ratio
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// clockwise, in degrees
+    Rotate(u32),
+    Grayscale,
+    Blur(f32),
+}
+
+fn arg<T: FromStr>(op: &str, args: &[&str], index: usize) -> Result<T, StatusError> {
+    args.get(index)
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| StatusError::bad_request(format!("Bad arguments for {op}")))
+}
+
+impl FromStr for Transform {
+    type Err = StatusError;
+
+    /// `name:arg:arg`, e.g. `resize:200:100`, `crop:0:0:50:50`, `rotate:90`,
+    /// `grayscale` or `blur:2.5`
+    fn from_str(op: &str) -> Result<Self, Self::Err> {
+        let mut parts = op.split(':');
+        let name = parts.next().unwrap_or_default();
+        let args: Vec<&str> = parts.collect();
+        let expect = |count: usize| {
+            if args.len() == count {
+                Ok(())
+            } else {
+                Err(StatusError::bad_request(format!(
+                    "{name} takes {count} arguments"
+                )))
+            }
+        };
+        let transform = match name {
+            "resize" => {
+                expect(2)?;
+                Transform::Resize {
+                    width: arg(name, &args, 0)?,
+                    height: arg(name, &args, 1)?,
+                }
+            }
+            "crop" => {
+                expect(4)?;
+                Transform::Crop {
+                    x: arg(name, &args, 0)?,
+                    y: arg(name, &args, 1)?,
+                    width: arg(name, &args, 2)?,
+                    height: arg(name, &args, 3)?,
+                }
+            }
+            "rotate" => {
+                expect(1)?;
+                Transform::Rotate(arg(name, &args, 0)?)
+            }
+            "grayscale" => {
+                expect(0)?;
+                Transform::Grayscale
+            }
+            "blur" => {
+                expect(1)?;
+                Transform::Blur(arg(name, &args, 0)?)
+            }
+            _ => {
+                return Err(StatusError::bad_request(format!(
+                    "Unknown transform {name}"
+                )))
+            }
+        };
+        Ok(transform)
+    }
+}
+
+impl Transform {
+    fn apply(self, image: DynamicImage) -> Result<DynamicImage, StatusError> {
+        let transformed = match self {
+            Transform::Resize { width, height } => {
+                if !(1..=MAX_SIDE).contains(&width) || !(1..=MAX_SIDE).contains(&height) {
+                    return Err(StatusError::bad_request(format!(
+                        "resize sides must be between 1 and {MAX_SIDE}"
+                    )));
+                }
+                image.resize(width, height, FilterType::Triangle)
+            }
+            Transform::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let fits = x.checked_add(width).is_some_and(|r| r <= image.width())
+                    && y.checked_add(height).is_some_and(|b| b <= image.height());
+                if !fits || width == 0 || height == 0 {
+                    return Err(StatusError::bad_request(format!(
+                        "crop doesn't fit in the {}x{} image",
+                        image.width(),
+                        image.height()
+                    )));
+                }
+                image.crop_imm(x, y, width, height)
+            }
+            Transform::Rotate(90) => image.rotate90(),
+            Transform::Rotate(180) => image.rotate180(),
+            Transform::Rotate(270) => image.rotate270(),
+            Transform::Rotate(_) => {
+                return Err(StatusError::bad_request(
+                    "rotate takes 90, 180 or 270 degrees",
+                ))
+            }
+            Transform::Grayscale => image.grayscale(),
+            Transform::Blur(sigma) => {
+                if !(sigma > 0.0 && sigma <= MAX_SIGMA) {
+                    return Err(StatusError::bad_request(format!(
+                        "blur takes a sigma between 0 and {MAX_SIGMA}"
+                    )));
+                }
+                image.blur(sigma)
+            }
+        };
+        Ok(transformed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+}
+
+impl OutputFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Gif => ImageFormat::Gif,
+            OutputFormat::Bmp => ImageFormat::Bmp,
+        }
+    }
+
+    /// The format to answer in when none is asked for, the input's when it
+    /// can be written
+    pub fn from_input(format: Option<ImageFormat>) -> Self {
+        match format {
+            Some(ImageFormat::Jpeg) => OutputFormat::Jpeg,
+            Some(ImageFormat::Gif) => OutputFormat::Gif,
+            Some(ImageFormat::Bmp) => OutputFormat::Bmp,
+            _ => OutputFormat::Png,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        self.image_format().extensions_str()[0]
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        self.image_format().to_mime_type()
+    }
+}
+
+/// A parsed `ops` query parameter, transforms separated by commas
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chain(pub Vec<Transform>);
+
+impl FromStr for Chain {
+    type Err = StatusError;
+
+    fn from_str(ops: &str) -> Result<Self, Self::Err> {
+        let chain = ops
+            .split(',')
+            .filter(|op| !op.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if chain.len() > MAX_OPS {
+            return Err(StatusError::bad_request(format!(
+                "At most {MAX_OPS} transforms can be chained"
+            )));
+        }
+        Ok(Chain(chain))
+    }
+}
+
+impl Chain {
+    /// Applies every transform in order and encodes the result
+    pub fn run(&self, image: DynamicImage, format: OutputFormat) -> Result<Vec<u8>, Report> {
+        let image = self
+            .0
+            .iter()
+            .try_fold(image, |image, transform| transform.apply(image))?;
+        // jpeg has no alpha channel
+        let image = match format {
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+            _ => image,
+        };
+        let mut encoded = Cursor::new(vec![]);
+        image.write_to(&mut encoded, format.image_format())?;
+        Ok(encoded.into_inner())
+    }
+}
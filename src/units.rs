@@ -0,0 +1,381 @@
+use serde::{Deserialize, Serialize};
+
+use crate::cch_error::StatusError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Mass,
+    Volume,
+    Count,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Mg,
+    G,
+    Kg,
+    Oz,
+    Lb,
+    Ml,
+    L,
+    Tsp,
+    Tbsp,
+    Cup,
+    Pcs,
+    Dozen,
+}
+
+impl Unit {
+    pub fn dimension(self) -> Dimension {
+        match self {
+            Unit::Mg | Unit::G | Unit::Kg | Unit::Oz | Unit::Lb => Dimension::Mass,
+            Unit::Ml | Unit::L | Unit::Tsp | Unit::Tbsp | Unit::Cup => Dimension::Volume,
+            Unit::Pcs | Unit::Dozen => Dimension::Count,
+        }
+    }
+
+    /// size of the unit in grams, millilitres or pieces
+    fn factor(self) -> f64 {
+        match self {
+            Unit::Mg => 0.001,
+            Unit::G => 1.0,
+            Unit::Kg => 1000.0,
+            Unit::Oz => 28.349_523_125,
+            Unit::Lb => 453.592_37,
+            Unit::Ml => 1.0,
+            Unit::L => 1000.0,
+            Unit::Tsp => 4.928_921_593_75,
+            Unit::Tbsp => 14.786_764_781_25,
+            Unit::Cup => 236.588_236_5,
+            Unit::Pcs => 1.0,
+            Unit::Dozen => 12.0,
+        }
+    }
+}
+
+/// Approximate densities in grams per millilitre for converting
+/// between volume and mass
+const DENSITIES: &[(&str, f64)] = &[
+    ("flour", 0.529),
+    ("sugar", 0.845),
+    ("brown sugar", 0.93),
+    ("powdered sugar", 0.507),
+    ("butter", 0.959),
+    ("baking powder", 0.9),
+    ("baking soda", 1.0),
+    ("chocolate chips", 0.72),
+    ("cocoa", 0.42),
+    ("salt", 1.217),
+    ("milk", 1.03),
+    ("water", 1.0),
+    ("oil", 0.92),
+    ("honey", 1.42),
+    ("oats", 0.34),
+];
+
+pub fn density(ingredient: &str) -> Option<f64> {
+    DENSITIES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(ingredient))
+        .map(|(_, density)| *density)
+}
+
+/// Converts `amount` of `ingredient` between units, going through the
+/// ingredient's density when one unit is a mass and the other a volume
+pub fn convert(ingredient: &str, amount: f64, from: Unit, to: Unit) -> Result<f64, StatusError> {
+    let base = amount * from.factor();
+    let base = match (from.dimension(), to.dimension()) {
+        (a, b) if a == b => base,
+        (Dimension::Volume, Dimension::Mass) => base * known_density(ingredient)?,
+        (Dimension::Mass, Dimension::Volume) => base / known_density(ingredient)?,
+        _ => {
+            return Err(StatusError::bad_request(format!(
+                "Can't convert {ingredient} from {} to {}",
+                format!("{from:?}").to_lowercase(),
+                format!("{to:?}").to_lowercase()
+            )))
+        }
+    };
+    Ok(base / to.factor())
+}
+
+fn known_density(ingredient: &str) -> Result<f64, StatusError> {
+    density(ingredient)
+        .ok_or_else(|| StatusError::bad_request(format!("No density known for {ingredient}")))
+}
+
+/// An amount of an ingredient, bare numbers have no unit and take the
+/// unit of whatever they are compared with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged, try_from = "RawQuantity")]
+pub enum Quantity {
+    Plain(u64),
+    Measured { amount: f64, unit: Unit },
+}
+
+/// A quantity as sent, before its amount is checked
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawQuantity {
+    Plain(u64),
+    Measured { amount: f64, unit: Unit },
+}
+
+/// Measured amounts can't be negative, they would put stock back in. Zero
+/// is kept since that is what an emptied pantry is stored as.
+impl TryFrom<RawQuantity> for Quantity {
+    type Error = String;
+
+    fn try_from(raw: RawQuantity) -> Result<Self, Self::Error> {
+        match raw {
+            RawQuantity::Plain(amount) => Ok(Quantity::Plain(amount)),
+            RawQuantity::Measured { amount, unit } if amount.is_finite() && amount >= 0.0 => {
+                Ok(Quantity::Measured { amount, unit })
+            }
+            RawQuantity::Measured { amount, .. } => Err(format!("Invalid amount {amount}")),
+        }
+    }
+}
+
+impl Quantity {
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Quantity::Plain(amount) => *amount == 0,
+            Quantity::Measured { amount, .. } => *amount == 0.0,
+        }
+    }
+
+    pub fn amount(&self) -> f64 {
+        match self {
+            Quantity::Plain(amount) => *amount as f64,
+            Quantity::Measured { amount, .. } => *amount,
+        }
+    }
+
+    pub fn unit(&self) -> Option<Unit> {
+        match self {
+            Quantity::Plain(_) => None,
+            Quantity::Measured { unit, .. } => Some(*unit),
+        }
+    }
+
+    /// `amount`, given in this quantity's unit, in the same form as this
+    /// quantity. Plain quantities stay plain and drop any fraction.
+    pub fn with_amount(&self, amount: f64) -> Quantity {
+        match self {
+            Quantity::Plain(_) => Quantity::Plain(amount.floor() as u64),
+            Quantity::Measured { unit, .. } => Quantity::Measured {
+                amount,
+                unit: *unit,
+            },
+        }
+    }
+
+    /// The sum of both amounts in this amount's unit, a plain sum drops any
+    /// fraction
+    pub fn add(&self, ingredient: &str, other: &Quantity) -> Result<Quantity, StatusError> {
+        let too_much = || StatusError::bad_request(format!("Too much {ingredient} to store"));
+        let (a, b) = match in_common_unit(ingredient, self, other)? {
+            CommonAmounts::Plain(a, b) => {
+                return a.checked_add(b).map(Quantity::Plain).ok_or_else(too_much)
+            }
+            CommonAmounts::Measured(a, b, _) => (a, b),
+        };
+        let sum = a + b;
+        if !sum.is_finite() || (self.unit().is_none() && sum >= u64::MAX as f64) {
+            return Err(too_much());
+        }
+        Ok(self.with_amount(sum))
+    }
+
+    /// This amount expressed in `unit`, a plain amount is already in it
+    pub fn amount_in(&self, ingredient: &str, unit: Unit) -> Result<f64, StatusError> {
+        match self {
+            Quantity::Plain(amount) => Ok(*amount as f64),
+            Quantity::Measured { amount, unit: from } => convert(ingredient, *amount, *from, unit),
+        }
+    }
+}
+
+/// Two amounts of an ingredient in a shared unit, plain amounts are kept
+/// as integers when neither has a unit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommonAmounts {
+    Plain(u64, u64),
+    Measured(f64, f64, Unit),
+}
+
+/// The pantry and recipe amounts of `ingredient` in a shared unit.
+/// That is the pantry's unit, or the recipe's when the pantry amount is plain.
+pub fn in_common_unit(
+    ingredient: &str,
+    pantry: &Quantity,
+    recipe: &Quantity,
+) -> Result<CommonAmounts, StatusError> {
+    match (pantry, recipe) {
+        (Quantity::Plain(a), Quantity::Plain(b)) => Ok(CommonAmounts::Plain(*a, *b)),
+        (Quantity::Measured { unit, .. }, _) | (_, Quantity::Measured { unit, .. }) => {
+            Ok(CommonAmounts::Measured(
+                pantry.amount_in(ingredient, *unit)?,
+                recipe.amount_in(ingredient, *unit)?,
+                *unit,
+            ))
+        }
+    }
+}
+
+/// Measured amounts are kept to a millionth of their unit, which trims the
+/// rounding error of unit conversions
+const PRECISION: f64 = 1e6;
+/// how far a converted ratio may miss a whole number and still count as one
+const SLACK: f64 = 1e-9;
+
+/// `amount` trimmed to `PRECISION`, anything below zero is nothing
+pub fn trim(amount: f64) -> f64 {
+    (amount.max(0.0) * PRECISION).round() / PRECISION
+}
+
+/// `stock` less `used`, trimmed. None when more is used than there is.
+pub fn remaining(stock: f64, used: f64) -> Option<f64> {
+    let left = stock - used;
+    (left.is_finite() && left * PRECISION >= -1.0).then(|| trim(left))
+}
+
+/// How many whole `part`s fit in `whole`
+pub fn times_within(whole: f64, part: f64) -> u64 {
+    (whole / part + SLACK).floor().max(0.0) as u64
+}
+
+/// How many `part`s it takes to cover `whole`
+pub fn times_to_cover(whole: f64, part: f64) -> u64 {
+    (whole / part - SLACK).ceil().max(0.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn converts_within_a_dimension() {
+        assert_close(convert("flour", 1.5, Unit::Kg, Unit::G).unwrap(), 1500.0);
+        assert_close(convert("sugar", 1.0, Unit::Lb, Unit::Oz).unwrap(), 16.0);
+        assert_close(convert("milk", 3.0, Unit::Tsp, Unit::Tbsp).unwrap(), 1.0);
+        assert_close(convert("eggs", 2.0, Unit::Dozen, Unit::Pcs).unwrap(), 24.0);
+    }
+
+    #[test]
+    fn converts_through_density() {
+        assert_close(convert("water", 1.0, Unit::L, Unit::Kg).unwrap(), 1.0);
+        assert_close(
+            convert("flour", 1.0, Unit::Cup, Unit::G).unwrap(),
+            236.588_236_5 * 0.529,
+        );
+        assert_close(convert("Butter", 95.9, Unit::G, Unit::Ml).unwrap(), 100.0);
+    }
+
+    #[test]
+    fn refuses_impossible_conversions() {
+        let unknown = convert("unobtainium", 1.0, Unit::Cup, Unit::G).unwrap_err();
+        assert_eq!(unknown.message, "No density known for unobtainium");
+        let count = convert("flour", 1.0, Unit::Pcs, Unit::G).unwrap_err();
+        assert_eq!(count.message, "Can't convert flour from pcs to g");
+    }
+
+    #[test]
+    fn common_unit_prefers_the_pantry() {
+        let pantry = Quantity::Measured {
+            amount: 1.0,
+            unit: Unit::Kg,
+        };
+        let recipe = Quantity::Measured {
+            amount: 250.0,
+            unit: Unit::G,
+        };
+        let CommonAmounts::Measured(a, b, unit) =
+            in_common_unit("flour", &pantry, &recipe).unwrap()
+        else {
+            panic!("measured amounts became plain");
+        };
+        assert_close(a, 1.0);
+        assert_close(b, 0.25);
+        assert_eq!(unit, Unit::Kg);
+
+        assert_eq!(
+            in_common_unit("flour", &Quantity::Plain(500), &recipe).unwrap(),
+            CommonAmounts::Measured(500.0, 250.0, Unit::G)
+        );
+        assert_eq!(
+            in_common_unit("flour", &Quantity::Plain(5), &Quantity::Plain(2)).unwrap(),
+            CommonAmounts::Plain(5, 2)
+        );
+    }
+
+    #[test]
+    fn conversion_error_is_trimmed() {
+        let cups = convert("flour", 3.0, Unit::Tbsp, Unit::Cup).unwrap() * 16.0;
+        assert_eq!(times_within(cups, 3.0), 1);
+        assert_eq!(times_to_cover(cups, 3.0), 1);
+        assert_eq!(trim(cups), 3.0);
+        assert_eq!(remaining(1.0, 1.000_000_1), Some(0.0));
+        assert_eq!(remaining(1.0, 1.1), None);
+        assert_eq!(remaining(1.0, 0.25), Some(0.75));
+    }
+
+    #[test]
+    fn sums_take_the_first_amount_unit() {
+        let grams = Quantity::Measured {
+            amount: 250.0,
+            unit: Unit::G,
+        };
+        assert_eq!(
+            grams.add("flour", &Quantity::Plain(50)).unwrap(),
+            Quantity::Measured {
+                amount: 300.0,
+                unit: Unit::G,
+            }
+        );
+        assert_eq!(
+            Quantity::Plain(50).add("flour", &grams).unwrap(),
+            Quantity::Plain(300)
+        );
+        assert!(Quantity::Plain(u64::MAX)
+            .add("flour", &Quantity::Plain(1))
+            .is_err());
+    }
+
+    #[test]
+    fn plain_quantities_stay_plain() {
+        assert_eq!(Quantity::Plain(7).with_amount(2.75), Quantity::Plain(2));
+        let measured = Quantity::Measured {
+            amount: 1.0,
+            unit: Unit::Cup,
+        };
+        assert_eq!(
+            measured.with_amount(2.75),
+            Quantity::Measured {
+                amount: 2.75,
+                unit: Unit::Cup,
+            }
+        );
+    }
+
+    #[test]
+    fn negative_amounts_are_rejected() {
+        let parse = serde_json::from_str::<Quantity>;
+        assert!(parse(r#"{"amount": -1.5, "unit": "g"}"#).is_err());
+        assert!(parse("-1").is_err());
+        assert_eq!(
+            parse(r#"{"amount": 0, "unit": "g"}"#).unwrap(),
+            Quantity::Measured {
+                amount: 0.0,
+                unit: Unit::G,
+            }
+        );
+        assert_eq!(parse("3").unwrap(), Quantity::Plain(3));
+    }
+}
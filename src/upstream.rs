@@ -0,0 +1,466 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, Json};
+use color_eyre::eyre::WrapErr;
+use rand::Rng;
+use reqwest::{Client, ClientBuilder, Response};
+use serde::Serialize;
+use shuttle_secrets::SecretStore;
+use tracing::warn;
+
+use crate::{
+    cch_error::{ReportError, StatusError},
+    ServerState,
+};
+
+/// How calls to one upstream are timed out, retried and cut off
+#[derive(Debug, Clone)]
+pub struct UpstreamPolicy {
+    pub timeout: Duration,
+    /// attempts per call, including the first
+    pub attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// shortest wait between two attempts, jitter included
+    pub min_delay: Duration,
+    /// whether 429 responses are retried, a `Retry-After` longer than
+    /// `max_delay` is never waited for
+    pub retry_rate_limited: bool,
+    /// consecutive failed calls that open the circuit
+    pub failure_threshold: u32,
+    /// how long an open circuit fails fast before a trial call
+    pub cooldown: Duration,
+}
+
+impl UpstreamPolicy {
+    pub fn new(timeout: Duration) -> Self {
+        UpstreamPolicy {
+            timeout,
+            attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            min_delay: Duration::ZERO,
+            retry_rate_limited: true,
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+
+    /// For upstreams that allow a request a second or so: attempts are at
+    /// least `min_delay` apart and a 429 is never retried
+    pub fn rate_limited(mut self, min_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.max_delay = self.max_delay.max(min_delay);
+        self.retry_rate_limited = false;
+        self
+    }
+
+    /// Overrides the defaults with `{prefix}_TIMEOUT_MS`, `{prefix}_ATTEMPTS`,
+    /// `{prefix}_FAILURE_THRESHOLD` and `{prefix}_COOLDOWN_SECS` when set
+    pub fn with_secrets(mut self, secret_store: &SecretStore, prefix: &str) -> Self {
+        let setting = |name: &str| {
+            secret_store
+                .get(&format!("{prefix}_{name}"))
+                .and_then(|value| value.parse::<u64>().ok())
+        };
+        if let Some(ms) = setting("TIMEOUT_MS") {
+            self.timeout = Duration::from_millis(ms);
+        }
+        if let Some(attempts) = setting("ATTEMPTS") {
+            self.attempts = attempts.max(1) as u32;
+        }
+        if let Some(threshold) = setting("FAILURE_THRESHOLD") {
+            self.failure_threshold = threshold.max(1) as u32;
+        }
+        if let Some(secs) = setting("COOLDOWN_SECS") {
+            self.cooldown = Duration::from_secs(secs);
+        }
+        self
+    }
+
+    /// Exponential backoff capped at `max_delay`, with full jitter on its upper half
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        delay
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+            .max(self.min_delay)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// one trial call is let through to see if the upstream is back
+    HalfOpen,
+}
+
+/// The circuit of one upstream, times are passed in so it can be stepped
+/// through without waiting
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    /// times the circuit has opened
+    trips: u64,
+}
+
+impl Breaker {
+    fn new() -> Self {
+        Breaker {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            trips: 0,
+        }
+    }
+
+    fn remaining_cooldown(&self, cooldown: Duration, now: Instant) -> Duration {
+        self.opened_at.map_or(Duration::ZERO, |opened| {
+            cooldown.saturating_sub(now.saturating_duration_since(opened))
+        })
+    }
+
+    /// Lets a call through, or gives how long the circuit stays open
+    fn admit(&mut self, cooldown: Duration, now: Instant) -> Result<(), Duration> {
+        let remaining = self.remaining_cooldown(cooldown, now);
+        match self.state {
+            CircuitState::Closed => Ok(()),
+            // a trial that never reported back is replaced after another cooldown
+            CircuitState::Open | CircuitState::HalfOpen if remaining.is_zero() => {
+                self.state = CircuitState::HalfOpen;
+                self.opened_at = Some(now);
+                Ok(())
+            }
+            CircuitState::Open | CircuitState::HalfOpen => Err(remaining),
+        }
+    }
+
+    /// Records the outcome of a call, true when a failure opened the circuit
+    fn record(&mut self, success: bool, failure_threshold: u32, now: Instant) -> bool {
+        if success {
+            self.state = CircuitState::Closed;
+            self.consecutive_failures = 0;
+            self.opened_at = None;
+            return false;
+        }
+        self.consecutive_failures += 1;
+        let trial_failed = self.state == CircuitState::HalfOpen;
+        if !trial_failed && self.consecutive_failures < failure_threshold {
+            return false;
+        }
+        let opened = self.state != CircuitState::Open;
+        if opened {
+            self.trips += 1;
+        }
+        self.state = CircuitState::Open;
+        self.opened_at = Some(now);
+        opened
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatus {
+    name: &'static str,
+    state: CircuitState,
+    consecutive_failures: u32,
+    trips: u64,
+    /// seconds until a trial call is let through
+    retry_after: Option<u64>,
+}
+
+/// An HTTP upstream behind a timeout, retries and a circuit breaker
+#[derive(Debug)]
+pub struct Upstream {
+    name: &'static str,
+    client: Client,
+    policy: UpstreamPolicy,
+    breaker: Mutex<Breaker>,
+}
+
+impl Upstream {
+    pub fn new(name: &'static str, policy: UpstreamPolicy) -> Result<Self, reqwest::Error> {
+        let client = ClientBuilder::new()
+            .user_agent("cch23-shuttle/1.0.0")
+            .timeout(policy.timeout)
+            .build()?;
+        Ok(Upstream {
+            name,
+            client,
+            policy,
+            breaker: Mutex::new(Breaker::new()),
+        })
+    }
+
+    /// Fails fast with a 503 while the circuit is open
+    fn admit(&self) -> Result<(), StatusError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker
+            .admit(self.policy.cooldown, Instant::now())
+            .map_err(|remaining| {
+                StatusError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("{} is unavailable", self.name),
+                )
+                .with_retry_after(remaining.as_secs().max(1))
+            })
+    }
+
+    fn record(&self, success: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.record(success, self.policy.failure_threshold, Instant::now()) {
+            warn!(upstream = self.name, "circuit opened");
+        }
+    }
+
+    /// GETs `url`, retrying timeouts, connection errors, 5xx and, when the
+    /// policy allows it, 429 responses. Retries wait at least as long as the
+    /// upstream's `Retry-After`.
+    ///
+    /// Any other response is handed back as is, so callers still decide what
+    /// a 404 means.
+    pub async fn get(&self, url: &str) -> Result<Response, ReportError> {
+        self.admit()?;
+        let mut retry = 0;
+        loop {
+            let result = self.client.get(url).send().await;
+            let status = result.as_ref().ok().map(Response::status);
+            let rate_limited = status == Some(reqwest::StatusCode::TOO_MANY_REQUESTS);
+            let transient = match status {
+                Some(status) => {
+                    status.is_server_error() || (rate_limited && self.policy.retry_rate_limited)
+                }
+                None => true,
+            };
+            if !transient && !rate_limited {
+                self.record(true);
+                return Ok(result?);
+            }
+            let retry_after = result.as_ref().ok().and_then(retry_after);
+            let waits_too_long =
+                retry_after.is_some_and(|secs| Duration::from_secs(secs) > self.policy.max_delay);
+            retry += 1;
+            if !transient || waits_too_long || retry >= self.policy.attempts {
+                self.record(false);
+                let response = result.wrap_err(StatusError::new(
+                    StatusCode::BAD_GATEWAY,
+                    format!("{} is unreachable", self.name),
+                ))?;
+                let status = response.status();
+                let error = if rate_limited {
+                    StatusError::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        format!("{} is rate limiting us", self.name),
+                    )
+                } else {
+                    StatusError::new(
+                        StatusCode::BAD_GATEWAY,
+                        format!("{} returned {status}", self.name),
+                    )
+                };
+                return Err(match retry_after {
+                    Some(secs) => error.with_retry_after(secs),
+                    None => error,
+                }
+                .into());
+            }
+            let delay = self
+                .policy
+                .backoff(retry - 1)
+                .max(Duration::from_secs(retry_after.unwrap_or_default()));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub fn status(&self) -> UpstreamStatus {
+        let breaker = self.breaker.lock().unwrap();
+        let retry_after = (breaker.state != CircuitState::Closed).then(|| {
+            breaker
+                .remaining_cooldown(self.policy.cooldown, Instant::now())
+                .as_secs()
+        });
+        UpstreamStatus {
+            name: self.name,
+            state: breaker.state,
+            consecutive_failures: breaker.consecutive_failures,
+            trips: breaker.trips,
+            retry_after,
+        }
+    }
+}
+
+/// `Retry-After` in seconds, HTTP dates aren't supported
+fn retry_after(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[tracing::instrument(skip(state))]
+pub async fn upstream_status(State(state): State<ServerState>) -> Json<Vec<UpstreamStatus>> {
+    Json(vec![state.pokeapi.status(), state.nominatim.status()])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        http::header::RETRY_AFTER,
+        response::{IntoResponse, Response as AxumResponse},
+        routing::get,
+        Router,
+    };
+
+    use super::*;
+
+    #[test]
+    fn breaker_opens_trials_and_closes() {
+        let cooldown = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut breaker = Breaker::new();
+        assert!(!breaker.record(false, 2, start));
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert!(breaker.record(false, 2, start));
+        assert_eq!(breaker.state, CircuitState::Open);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(breaker.admit(cooldown, later), Err(Duration::from_secs(20)));
+        let after_cooldown = start + cooldown;
+        assert_eq!(breaker.admit(cooldown, after_cooldown), Ok(()));
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        // only the trial is let through
+        assert!(breaker.admit(cooldown, after_cooldown).is_err());
+
+        assert!(!breaker.record(true, 2, after_cooldown));
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+        assert_eq!(breaker.admit(cooldown, after_cooldown), Ok(()));
+        assert_eq!(breaker.trips, 1);
+    }
+
+    #[test]
+    fn a_failed_trial_reopens_the_circuit() {
+        let cooldown = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut breaker = Breaker::new();
+        breaker.record(false, 1, start);
+        let trial = start + cooldown;
+        breaker.admit(cooldown, trial).unwrap();
+        assert!(breaker.record(false, 1, trial));
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert_eq!(breaker.trips, 2);
+        assert_eq!(breaker.admit(cooldown, trial), Err(cooldown));
+    }
+
+    /// Serves `responses` in turn, the last one repeats, and counts requests
+    async fn serve(
+        responses: Vec<(StatusCode, Option<&'static str>)>,
+    ) -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let seen = count.clone();
+        let app = Router::new().route(
+            "/",
+            get(move || async move {
+                let n = seen.fetch_add(1, Ordering::SeqCst);
+                let (status, retry_after) = responses[n.min(responses.len() - 1)];
+                let mut response: AxumResponse = status.into_response();
+                if let Some(secs) = retry_after {
+                    response
+                        .headers_mut()
+                        .insert(RETRY_AFTER, secs.parse().unwrap());
+                }
+                response
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, count)
+    }
+
+    fn policy() -> UpstreamPolicy {
+        let mut policy = UpstreamPolicy::new(Duration::from_secs(5));
+        policy.base_delay = Duration::from_millis(1);
+        policy
+    }
+
+    fn status_error(err: &ReportError) -> &StatusError {
+        err.0.downcast_ref::<StatusError>().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_wait_for_retry_after() {
+        let (url, count) = serve(vec![
+            (StatusCode::TOO_MANY_REQUESTS, Some("1")),
+            (StatusCode::OK, None),
+        ])
+        .await;
+        let upstream = Upstream::new("test", policy()).unwrap();
+        let start = Instant::now();
+        let Ok(response) = upstream.get(&url).await else {
+            panic!("the retry failed");
+        };
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_become_503_with_retry_after() {
+        let (url, count) = serve(vec![(StatusCode::TOO_MANY_REQUESTS, Some("7"))]).await;
+        let upstream =
+            Upstream::new("test", policy().rate_limited(Duration::from_millis(1))).unwrap();
+        let Err(err) = upstream.get(&url).await else {
+            panic!("a 429 was handed back");
+        };
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status_error(&err).retry_after, Some(7));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // too long to wait for even when 429s are retried
+        let upstream = Upstream::new("test", policy()).unwrap();
+        let Err(err) = upstream.get(&url).await else {
+            panic!("a 429 was handed back");
+        };
+        assert_eq!(status_error(&err).retry_after, Some(7));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn an_open_circuit_fails_fast() {
+        let (url, count) = serve(vec![(StatusCode::INTERNAL_SERVER_ERROR, None)]).await;
+        let mut policy = policy();
+        policy.failure_threshold = 1;
+        let upstream = Upstream::new("test", policy).unwrap();
+        let Err(err) = upstream.get(&url).await else {
+            panic!("a 500 was handed back");
+        };
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!(upstream.status().state, CircuitState::Open);
+
+        let Err(err) = upstream.get(&url).await else {
+            panic!("the open circuit let a call through");
+        };
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(matches!(status_error(&err).retry_after, Some(29 | 30)));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+}